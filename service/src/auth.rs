@@ -0,0 +1,69 @@
+#[cfg(any(feature = "client", feature = "jwt"))]
+use tokio_tungstenite::tungstenite::Message;
+
+///Маркер служебного фрейма авторизации, формат: маркер, тип фрейма, данные
+const AUTH_MARKER: u8 = 0xA5;
+#[cfg(any(feature = "client", feature = "jwt"))]
+const REQUEST: u8 = 1;
+#[cfg(any(feature = "client", feature = "jwt"))]
+const TOKEN: u8 = 2;
+
+///Служебный фрейм обновления токена без переподключения
+#[cfg(any(feature = "client", feature = "jwt"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AuthFrame
+{
+    ///сервер просит клиента прислать новый токен, срок действия текущего истек
+    Request,
+    ///новый токен клиента
+    Token(String)
+}
+
+pub(crate) fn is_auth_frame(data: &[u8]) -> bool
+{
+    data.first() == Some(&AUTH_MARKER)
+}
+#[cfg(any(feature = "client", feature = "jwt"))]
+pub(crate) fn encode(frame: &AuthFrame) -> Message
+{
+    let mut message = vec![AUTH_MARKER];
+    match frame
+    {
+        AuthFrame::Request => message.push(REQUEST),
+        AuthFrame::Token(token) =>
+        {
+            message.push(TOKEN);
+            message.extend_from_slice(token.as_bytes());
+        }
+    }
+    Message::Binary(message)
+}
+#[cfg(any(feature = "client", feature = "jwt"))]
+pub(crate) fn decode(data: &[u8]) -> Option<AuthFrame>
+{
+    match data.get(..2)?
+    {
+        [AUTH_MARKER, REQUEST] => Some(AuthFrame::Request),
+        [AUTH_MARKER, TOKEN] => String::from_utf8(data[2..].to_vec()).ok().map(AuthFrame::Token),
+        _ => None
+    }
+}
+
+#[cfg(all(test, any(feature = "client", feature = "jwt")))]
+mod tests
+{
+    use super::{decode, encode, is_auth_frame, AuthFrame};
+
+    #[test]
+    fn test_frames()
+    {
+        for frame in [AuthFrame::Request, AuthFrame::Token("a.b.c".to_owned())]
+        {
+            let data = encode(&frame).into_data();
+            assert!(is_auth_frame(&data));
+            assert_eq!(decode(&data), Some(frame));
+        }
+        assert_eq!(decode(&[0xA5, 9]), None);
+        assert!(!is_auth_frame(b"{}"));
+    }
+}
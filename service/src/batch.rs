@@ -0,0 +1,179 @@
+use std::time::Duration;
+use anyhow::anyhow;
+use futures::{stream::{self, BoxStream}, Stream, StreamExt};
+use futures_channel::mpsc::UnboundedReceiver;
+use tokio_tungstenite::tungstenite::Message;
+use crate::transport::Outgoing;
+
+///Первый байт пакета сообщений, json не может начинаться с этого байта
+const BATCH_MARKER: u8 = 0xBA;
+
+///Настройки пакетной отправки мелких сообщений
+/// __max_items__ максимальное количество сообщений в одном фрейме
+/// __window__ сколько ждать следующие сообщения прежде чем отправить пакет
+#[derive(Debug, Clone, Copy)]
+pub struct BatchSettings
+{
+    pub max_items: usize,
+    pub window: Duration
+}
+impl Default for BatchSettings
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            max_items: 64,
+            window: Duration::from_millis(5)
+        }
+    }
+}
+
+///Упаковка нескольких сообщений в один фрейм
+/// формат: маркер, затем для каждого сообщения длина (u32 be) и само сообщение
+pub(crate) fn pack(items: &[Vec<u8>]) -> Vec<u8>
+{
+    let len = items.iter().map(|i| i.len() + 4).sum::<usize>() + 1;
+    let mut packed = Vec::with_capacity(len);
+    packed.push(BATCH_MARKER);
+    for item in items
+    {
+        packed.extend_from_slice(&(item.len() as u32).to_be_bytes());
+        packed.extend_from_slice(item);
+    }
+    packed
+}
+
+///Распаковка фрейма, если фрейм не является пакетом то возвращается он сам
+pub(crate) fn unpack(data: &[u8]) -> anyhow::Result<Vec<&[u8]>>
+{
+    if data.first() != Some(&BATCH_MARKER)
+    {
+        return Ok(vec![data]);
+    }
+    let mut items = Vec::new();
+    let mut rest = &data[1..];
+    while !rest.is_empty()
+    {
+        if rest.len() < 4
+        {
+            return Err(anyhow!("Пакет сообщений поврежден, не удалось прочитать длину сообщения"));
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        rest = &rest[4..];
+        if rest.len() < len
+        {
+            return Err(anyhow!("Пакет сообщений поврежден, ожидалось {} байт, получено {}", len, rest.len()));
+        }
+        items.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    Ok(items)
+}
+
+///Поток исходящих сообщений, при включенной пакетной отправке
+/// бинарные сообщения поступившие в течении окна объединяются в один фрейм
+pub(crate) fn outgoing(receiver: UnboundedReceiver<Outgoing>, settings: Option<BatchSettings>) -> BoxStream<'static, Outgoing>
+{
+    if let Some(settings) = settings
+    {
+        batched(receiver, settings).boxed()
+    }
+    else
+    {
+        receiver.boxed()
+    }
+}
+
+fn batched(receiver: UnboundedReceiver<Outgoing>, settings: BatchSettings) -> impl Stream<Item = Outgoing>
+{
+    stream::unfold((receiver, None::<Outgoing>), move |(mut receiver, pending)| async move
+    {
+        let first = match pending
+        {
+            Some(m) => m,
+            None => receiver.next().await?
+        };
+        //служебные сообщения (ping, close) отправляются сразу
+        if !first.message.is_binary()
+        {
+            return Some((first, (receiver, None)));
+        }
+        let mut acks = first.acks;
+        let mut count = first.count;
+        let mut items = vec![first.message.into_data()];
+        let mut carry = None;
+        let deadline = tokio::time::Instant::now() + settings.window;
+        while items.len() < settings.max_items
+        {
+            match tokio::time::timeout_at(deadline, receiver.next()).await
+            {
+                Ok(Some(m)) if m.message.is_binary() =>
+                {
+                    acks.extend(m.acks);
+                    count += m.count;
+                    items.push(m.message.into_data());
+                },
+                //служебное сообщение отправим следующим, после текущего пакета
+                Ok(Some(m)) =>
+                {
+                    carry = Some(m);
+                    break;
+                },
+                Ok(None) | Err(_) => break
+            }
+        }
+        let message = if items.len() == 1
+        {
+            Message::Binary(items.pop().unwrap())
+        }
+        else
+        {
+            Message::Binary(pack(&items))
+        };
+        Some((Outgoing { message, acks, count }, (receiver, carry)))
+    })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use futures::StreamExt;
+    use futures_channel::mpsc::unbounded;
+    use tokio_tungstenite::tungstenite::Message;
+    use crate::transport::Outgoing;
+    use super::{outgoing, pack, unpack, BatchSettings};
+
+    #[test]
+    fn test_pack_unpack()
+    {
+        let items = vec![b"{\"a\":1}".to_vec(), Vec::new(), b"\"str\"".to_vec()];
+        let packed = pack(&items);
+        let unpacked = unpack(&packed).unwrap();
+        assert_eq!(unpacked, items.iter().map(|i| i.as_slice()).collect::<Vec<&[u8]>>());
+        let single = b"{\"a\":1}";
+        assert_eq!(unpack(single).unwrap(), vec![&single[..]]);
+        assert!(unpack(&packed[..packed.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_batched_stream()
+    {
+        let (sender, receiver) = unbounded();
+        for i in 0..10u8
+        {
+            sender.unbounded_send(Outgoing::control(Message::Binary(vec![b'0' + i]))).unwrap();
+        }
+        sender.unbounded_send(Outgoing::control(Message::Ping(vec![1]))).unwrap();
+        sender.unbounded_send(Outgoing::control(Message::Binary(b"1".to_vec()))).unwrap();
+        drop(sender);
+        let settings = BatchSettings { max_items: 4, ..Default::default() };
+        let messages: Vec<Message> = outgoing(receiver, Some(settings)).map(|f| f.message).collect().await;
+        //4 + 4 + 2, затем ping и последнее сообщение отдельно
+        assert_eq!(messages.len(), 5);
+        assert_eq!(unpack(&messages[0].clone().into_data()).unwrap().len(), 4);
+        assert_eq!(unpack(&messages[2].clone().into_data()).unwrap().len(), 2);
+        assert!(messages[3].is_ping());
+        assert_eq!(messages[4], Message::Binary(b"1".to_vec()));
+    }
+}
@@ -0,0 +1,228 @@
+use std::{collections::HashMap, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use anyhow::anyhow;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+
+///Первый байт фрагмента большого сообщения, json не может начинаться с этого байта
+const CHUNK_MARKER: u8 = 0xC4;
+///маркер, id (u64), номер фрагмента (u32), количество фрагментов (u32), общий размер (u64)
+const HEADER_LEN: usize = 1 + 8 + 4 + 4 + 8;
+static CHUNK_ID: AtomicU64 = AtomicU64::new(1);
+///Сколько сообщений одного соединения может собираться одновременно
+const MAX_PARTIAL: usize = 16;
+///Несобранное сообщение отбрасывается если его фрагменты не приходят дольше этого времени
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+///Настройки разбиения больших сообщений на фрагменты
+/// __threshold__ сообщения больше этого размера (в байтах) разбиваются на фрагменты
+/// __chunk_size__ размер одного фрагмента
+/// __max_payload__ максимальный размер собираемого из фрагментов сообщения на стороне получателя
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkSettings
+{
+    pub threshold: usize,
+    pub chunk_size: usize,
+    pub max_payload: usize
+}
+impl Default for ChunkSettings
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            threshold: 1024 * 1024,
+            chunk_size: 256 * 1024,
+            max_payload: 1024 * 1024 * 1024
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkDirection
+{
+    Send,
+    Receive
+}
+///Прогресс передачи большого сообщения
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkProgress
+{
+    pub id: u64,
+    pub direction: ChunkDirection,
+    ///сколько байт уже передано
+    pub transferred: usize,
+    pub total: usize
+}
+impl ChunkProgress
+{
+    pub fn is_complete(&self) -> bool
+    {
+        self.transferred >= self.total
+    }
+}
+
+pub(crate) fn is_chunk(data: &[u8]) -> bool
+{
+    data.first() == Some(&CHUNK_MARKER)
+}
+
+///Разбиение сообщения на фрагменты, для каждого фрагмента возвращается прогресс который будет достигнут после его отправки
+pub(crate) fn split(payload: &[u8], chunk_size: usize) -> Vec<(Message, ChunkProgress)>
+{
+    let id = CHUNK_ID.fetch_add(1, Ordering::Relaxed);
+    let chunk_size = chunk_size.max(1);
+    let count = payload.len().div_ceil(chunk_size) as u32;
+    let mut transferred = 0;
+    payload.chunks(chunk_size).enumerate().map(|(index, data)|
+    {
+        let mut frame = Vec::with_capacity(HEADER_LEN + data.len());
+        frame.push(CHUNK_MARKER);
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(&(index as u32).to_be_bytes());
+        frame.extend_from_slice(&count.to_be_bytes());
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        frame.extend_from_slice(data);
+        transferred += data.len();
+        let progress = ChunkProgress
+        {
+            id,
+            direction: ChunkDirection::Send,
+            transferred,
+            total: payload.len()
+        };
+        (Message::Binary(frame), progress)
+    }).collect()
+}
+
+struct Partial
+{
+    next_index: u32,
+    count: u32,
+    data: Vec<u8>,
+    ///время получения последнего фрагмента
+    updated: Instant
+}
+
+///Сборка сообщений из фрагментов, у каждого соединения свой экземпляр
+pub(crate) struct Reassembler
+{
+    max_payload: usize,
+    partial: HashMap<u64, Partial>
+}
+impl Reassembler
+{
+    pub fn new(max_payload: usize) -> Self
+    {
+        Self
+        {
+            max_payload,
+            partial: HashMap::new()
+        }
+    }
+    ///Добавление фрагмента, если сообщение собрано полностью то оно возвращается вместе с прогрессом
+    pub fn push(&mut self, frame: &[u8]) -> anyhow::Result<(ChunkProgress, Option<Vec<u8>>)>
+    {
+        if frame.len() < HEADER_LEN || !is_chunk(frame)
+        {
+            return Err(anyhow!("Фрагмент сообщения поврежден"));
+        }
+        let id = u64::from_be_bytes(frame[1..9].try_into()?);
+        let index = u32::from_be_bytes(frame[9..13].try_into()?);
+        let count = u32::from_be_bytes(frame[13..17].try_into()?);
+        let total = u64::from_be_bytes(frame[17..25].try_into()?) as usize;
+        let data = &frame[HEADER_LEN..];
+        if total > self.max_payload
+        {
+            return Err(anyhow!("Размер сообщения {} превышает допустимый {}", total, self.max_payload));
+        }
+        let now = Instant::now();
+        self.partial.retain(|_, p| now.duration_since(p.updated) < STALE_AFTER);
+        if index == 0
+        {
+            if self.partial.len() >= MAX_PARTIAL && !self.partial.contains_key(&id)
+            {
+                return Err(anyhow!("Одновременно собирается больше {} сообщений", MAX_PARTIAL));
+            }
+            //размер из заголовка не проверен, поэтому память выделяется по мере получения фрагментов
+            self.partial.insert(id, Partial { next_index: 0, count, data: Vec::new(), updated: now });
+        }
+        let partial = self.partial.get_mut(&id).ok_or_else(|| anyhow!("Получен фрагмент {} неизвестного сообщения {}", index, id))?;
+        if partial.next_index != index || partial.count != count || partial.data.len() + data.len() > total
+        {
+            self.partial.remove(&id);
+            return Err(anyhow!("Нарушен порядок фрагментов сообщения {}", id));
+        }
+        partial.data.extend_from_slice(data);
+        partial.next_index += 1;
+        partial.updated = now;
+        let progress = ChunkProgress
+        {
+            id,
+            direction: ChunkDirection::Receive,
+            transferred: partial.data.len(),
+            total
+        };
+        if partial.next_index == partial.count
+        {
+            let partial = self.partial.remove(&id).unwrap();
+            if partial.data.len() != total
+            {
+                return Err(anyhow!("Размер собранного сообщения {} отличается от заявленного {}", partial.data.len(), total));
+            }
+            return Ok((progress, Some(partial.data)));
+        }
+        Ok((progress, None))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::Duration;
+    use super::{split, Reassembler, MAX_PARTIAL, STALE_AFTER};
+
+    #[test]
+    fn test_split_reassemble()
+    {
+        let payload: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let chunks = split(&payload, 3000);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.last().unwrap().1.is_complete());
+        let mut reassembler = Reassembler::new(usize::MAX);
+        let mut result = None;
+        for (message, _) in &chunks
+        {
+            let (progress, complete) = reassembler.push(&message.clone().into_data()).unwrap();
+            assert!(progress.transferred <= payload.len());
+            result = complete;
+        }
+        assert_eq!(result.unwrap(), payload);
+        //фрагменты не по порядку отбрасываются
+        let mut reassembler = Reassembler::new(usize::MAX);
+        assert!(reassembler.push(&chunks[0].0.clone().into_data()).is_ok());
+        assert!(reassembler.push(&chunks[2].0.clone().into_data()).is_err());
+        let mut reassembler = Reassembler::new(100);
+        assert!(reassembler.push(&chunks[0].0.clone().into_data()).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partial_limits()
+    {
+        let mut reassembler = Reassembler::new(usize::MAX);
+        let first = |payload: &[u8]| split(payload, 10)[0].0.clone().into_data();
+        //заявленный размер не выделяется заранее
+        let mut huge = first(&[0; 100]);
+        huge[17..25].copy_from_slice(&(u64::MAX / 2).to_be_bytes());
+        assert!(reassembler.push(&huge).is_ok());
+        for _ in 1..MAX_PARTIAL
+        {
+            assert!(reassembler.push(&first(&[0; 100])).is_ok());
+        }
+        assert!(reassembler.push(&first(&[0; 100])).is_err());
+        //несобранные сообщения отбрасываются когда фрагменты перестают приходить
+        tokio::time::advance(STALE_AFTER + Duration::from_secs(1)).await;
+        assert!(reassembler.push(&first(&[0; 100])).is_ok());
+        assert_eq!(reassembler.partial.len(), 1);
+        assert_eq!(reassembler.partial.values().next().unwrap().data.capacity(), 10);
+    }
+}
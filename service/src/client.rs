@@ -1,183 +1,942 @@
-use std::collections::HashMap;
+use std::{marker::PhantomData, path::Path, sync::{Arc, Mutex}};
 use anyhow::Context;
-use futures::Future;
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{future, pin_mut, StreamExt};
+use dashmap::DashMap;
+use futures::{sink, Future, Sink, SinkExt};
+use futures_channel::mpsc;
+use futures_util::{future::{self, Either}, pin_mut, stream::BoxStream, StreamExt, TryStreamExt};
 use logger::{backtrace,  error};
-use once_cell::sync::OnceCell;
-use tokio::sync::Mutex;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-use crate::retry;
+use once_cell::sync::Lazy;
+use tokio::{sync::{broadcast, oneshot, watch, Semaphore}, time::{Duration, Instant}};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{client_async_with_config, tungstenite::{self, client::IntoClientRequest, error::UrlError, handshake::client::Response, http::HeaderMap, protocol::{frame::coding::CloseCode, CloseFrame, Message}}, WebSocketStream};
+#[cfg(unix)]
+use crate::unix;
+use crate::{auth::{self, AuthFrame}, failover::Endpoints, files::{self, Inbox}, heartbeat::{Busy, Heartbeat}, outbox::Outbox, state, transport::{self, Inbound, Io, Outbound, Writer}, SendError, BatchSettings, ClientState, ConnectError, DisconnectReason, Dispatch, ChunkProgress, ChunkSettings, ClientConfig, FileSettings, ReceivedFile, TransferError};
 
-static SENDER: OnceCell<Mutex<HashMap<String, UnboundedSender<Message>>>> = OnceCell::new();
-static IS_CONNECTED: OnceCell<Mutex<HashMap<String, bool>>> = OnceCell::new();
+///Последний запущенный экземпляр клиента для каждого id, через него работают статические методы трейта
+static CONNECTIONS: Lazy<DashMap<&'static str, Arc<Connection>>> = Lazy::new(DashMap::new);
+///Сколько раз пытаться продолжить отправку файла после разрыва соединения
+const FILE_RESUME_ATTEMPTS: u32 = 10;
+///Сколько входящих сообщений может ожидать чтения из потока `start_client_stream`
+const STREAM_CAPACITY: usize = 1024;
+///Сколько ждать ответа сервера на закрытие соединения
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+///Сколько `start_client` ждет первого подключения
+const START_WAIT: Duration = Duration::from_secs(1);
 
 
 pub trait Client<T> where T: serde::Serialize + Send + Sync, for <'de> T : serde::Deserialize<'de> + Sized + Send
 {
     fn get_id() -> &'static str;
-    fn start_client<F>(addr: &str, f:F)  -> impl Future<Output = ()> + Send
-    where F:  Send + Sync + Clone + 'static + Fn(T)
+    ///Настройки пакетной отправки сообщений, по умолчанию каждое сообщение отправляется отдельным фреймом
+    fn batching() -> Option<BatchSettings>
     {
-        let addr = addr.to_owned();
-        let cli_id = Self::get_id();
+        None
+    }
+    ///Настройки разбиения больших сообщений на фрагменты, по умолчанию сообщение отправляется одним фреймом
+    fn chunking() -> Option<ChunkSettings>
+    {
+        None
+    }
+    ///Прогресс отправки и получения сообщений разбитых на фрагменты
+    fn on_chunk_progress(_progress: ChunkProgress)
+    {
+    }
+    ///Настройки передачи файлов, по умолчанию прием файлов от сервера отключен
+    fn files() -> Option<FileSettings>
+    {
+        None
+    }
+    ///Запуск нового экземпляра клиента, каждый вызов открывает отдельное соединение,
+    /// статические методы трейта работают с последним запущенным экземпляром
+    fn start_client<F>(addr: &str, f:F)  -> impl Future<Output = ClientHandle<T>> + Send
+    where F:  Send + Sync + Clone + 'static + Fn(T), T: 'static
+    {
+        Self::start_client_with_config(addr, ClientConfig::default(), f)
+    }
+    ///Запуск клиента с асинхронным обработчиком входящих сообщений __f__, порядок вызова задается `ClientConfig::dispatch`
+    fn start_client_async<F, Fut>(addr: &str, f:F)  -> impl Future<Output = ClientHandle<T>> + Send
+    where F:  Send + Sync + Clone + 'static + Fn(T) -> Fut, Fut: Future<Output = ()> + Send + 'static, T: 'static
+    {
+        Self::start_client_with_config_async(addr, ClientConfig::default(), f)
+    }
+    ///Запуск клиента, возвращается после завершения первого рукопожатия с сервером, но не позже чем через секунду,
+    /// если за это время подключиться не удалось клиент продолжает попытки в фоне
+    fn start_client_with_config<F>(addr: &str, config: ClientConfig, f:F)  -> impl Future<Output = ClientHandle<T>> + Send
+    where F:  Send + Sync + Clone + 'static + Fn(T), T: 'static
+    {
+        Self::start_client_with_config_async(addr, config, move |msg: T|
+        {
+            f(msg);
+            future::ready(())
+        })
+    }
+    ///Запуск клиента с настройками __config__ и асинхронным обработчиком входящих сообщений __f__
+    fn start_client_with_config_async<F, Fut>(addr: &str, config: ClientConfig, f:F)  -> impl Future<Output = ClientHandle<T>> + Send
+    where F:  Send + Sync + Clone + 'static + Fn(T) -> Fut, Fut: Future<Output = ()> + Send + 'static, T: 'static
+    {
+        let launched = launch(Self::get_id(), addr.to_owned(), settings::<Self, T>(config), f);
+        async move
+        {
+            let (handle, handshake) = launched.await;
+            let _ = tokio::time::timeout(START_WAIT, handshake).await;
+            handle
+        }
+    }
+    ///Запуск клиента с ожиданием первого подключения не дольше __timeout__,
+    /// возвращает заголовки ответа сервера на рукопожатие  
+    /// если заданы резервные серверы то ошибка возвращается только если не удалось подключиться ни к одному из них,
+    /// при ошибке клиент останавливается  
+    /// __f__ асинхронный обработчик входящих сообщений как в `start_client_with_config_async`
+    fn connect<F, Fut>(addr: &str, config: ClientConfig, timeout: Duration, f:F)  -> impl Future<Output = Result<(ClientHandle<T>, HeaderMap), ConnectError>> + Send
+    where F:  Send + Sync + Clone + 'static + Fn(T) -> Fut, Fut: Future<Output = ()> + Send + 'static, T: 'static
+    {
+        let launched = launch(Self::get_id(), addr.to_owned(), settings::<Self, T>(config), f);
         async move
         {
-            tokio::spawn(async move
+            let (handle, handshake) = launched.await;
+            let result = match tokio::time::timeout(timeout, handshake).await
+            {
+                Ok(Ok(result)) => result,
+                Ok(Err(_)) => Err(ConnectError::Closed),
+                Err(_) => Err(ConnectError::Timeout)
+            };
+            match result
             {
-                loop
+                Ok(headers) => Ok((handle, headers)),
+                Err(e) =>
                 {
-                    start(cli_id, addr.clone(), f.clone(),0, 15).await;
+                    handle.close().await;
+                    Err(e)
                 }
-            });
-            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            }
         }
     }
-    fn is_connected() -> impl Future<Output = bool> + Send
+    ///Запуск клиента, входящие сообщения читаются из возвращаемого потока вместо обработчика  
+    /// пока сообщения не прочитаны из потока новые сообщения из соединения не читаются (`Dispatch::Inline`),
+    /// поток завершается когда клиент остановлен
+    fn start_client_stream(addr: &str, config: ClientConfig) -> impl Future<Output = (ClientHandle<T>, BoxStream<'static, T>)> + Send
+    where T: 'static
     {
-        async 
+        let (sender, receiver) = mpsc::channel(STREAM_CAPACITY);
+        let config = ClientConfig { dispatch: Dispatch::Inline, ..config };
+        let handle = Self::start_client_with_config_async(addr, config, move |msg: T|
         {
-            let guard = IS_CONNECTED.get_or_init(|| Mutex::new(HashMap::new())).lock().await;
-            if let Some(curr) = guard.get(Self::get_id())
-            {
-                return *curr
-            }
-            else 
+            let mut sender = sender.clone();
+            async move
             {
-                false
+                let _ = sender.send(msg).await;
             }
+        });
+        async move
+        {
+            (handle.await, receiver.boxed())
+        }
+    }
+    fn is_connected() -> impl Future<Output = bool> + Send
+    {
+        async
+        {
+            CONNECTIONS.get(Self::get_id()).map(|c| c.is_connected()).unwrap_or(false)
         }
     }
     fn send_message(wsmsg: T) -> impl Future<Output = ()> + Send
     {
-        async move 
+        async move
         {
-            if Self::is_connected().await
+            if let Some(handle) = current_handle::<T>(Self::get_id())
             {
-                let mut message: Vec<u8> = Vec::new();
-                let _ = serde_json::to_writer(&mut message, &wsmsg);
-                let message = Message::Binary(message);
-                let sender = SENDER.get().unwrap().lock().await;
-                let curr_sender = sender.get(Self::get_id()).unwrap();
-                let sended = curr_sender.unbounded_send(message);
-                if sended.is_ok()
-                {
-                    return ();
-                }
-                else
-                {
-                    logger::error!("Ошибка отправки сообщения {}", sended.err().unwrap());
-                    return ();
-                }
+                handle.send(wsmsg).await;
             }
-            else 
+            else
             {
                 logger::error!("Ошибка отправки сообщения, нет подключения к серверу");
-                return ();
             }
         }
     }
+    ///Отправка сообщения через последний запущенный экземпляр, см. `ClientHandle::try_send`
+    fn try_send_message(wsmsg: T) -> impl Future<Output = Result<(), SendError>> + Send
+    {
+        async move
+        {
+            current_handle::<T>(Self::get_id()).ok_or(SendError::NotConnected)?.try_send(wsmsg).await
+        }
+    }
+    ///Отправка сообщения через последний запущенный экземпляр, завершается когда сообщение записано в сокет
+    fn send_message_confirmed(wsmsg: T) -> impl Future<Output = Result<(), SendError>> + Send
+    {
+        async move
+        {
+            current_handle::<T>(Self::get_id()).ok_or(SendError::NotConnected)?.send_confirmed(wsmsg).await
+        }
+    }
+    ///Отправка файла на сервер, при разрыве соединения отправка продолжается после переподключения
+    /// с последней подтвержденной сервером позиции
+    fn send_file(path: impl AsRef<Path>) -> impl Future<Output = Result<(), TransferError>> + Send
+    {
+        let path = path.as_ref().to_owned();
+        let cli_id = Self::get_id();
+        async move
+        {
+            let handle = current_handle::<T>(cli_id).ok_or(TransferError::NotConnected)?;
+            handle.send_file(path).await
+        }
+    }
+    ///Ожидание следующего файла принятого от сервера, файлы принимаются только если заданы настройки `files`
+    fn receive_file() -> impl Future<Output = Option<ReceivedFile>> + Send
+    {
+        let cli_id = Self::get_id();
+        async move
+        {
+            current_handle::<T>(cli_id)?.receive_file().await
+        }
+    }
     fn ping() -> impl Future<Output = ()> + Send
     {
-        async 
+        async
+        {
+            if let Some(handle) = current_handle::<T>(Self::get_id())
+            {
+                handle.ping().await;
+            }
+            else
+            {
+                error!("Ошибка отправки сообщения, нет подключения к серверу");
+            }
+        }
+    }
+}
+
+fn current_handle<T>(cli_id: &str) -> Option<ClientHandle<T>>
+{
+    CONNECTIONS.get(cli_id).map(|c| ClientHandle::new(c.value().clone()))
+}
+
+///Состояние соединения общее для всех копий `ClientHandle`
+struct Connection
+{
+    id: &'static str,
+    ///очередь принятых файлов этого экземпляра
+    inbox: Arc<Inbox>,
+    files: FileSettings,
+    ///сообщения отправленные пока клиент не подключен
+    outbox: Option<tokio::sync::Mutex<Outbox>>,
+    sender: Mutex<Option<Outbound>>,
+    state: watch::Sender<ClientState>,
+    ///время последнего успешного подключения
+    connected_at: Mutex<Option<Instant>>,
+    ///все изменения состояния, для потока `state_changes`
+    events: broadcast::Sender<ClientState>,
+    closed: watch::Sender<bool>
+}
+impl Connection
+{
+    fn new(id: &'static str, endpoint: &str, files: FileSettings, outbox: Option<Outbox>) -> Self
+    {
+        Self
+        {
+            id,
+            inbox: Arc::new(Inbox::new()),
+            files,
+            outbox: outbox.map(tokio::sync::Mutex::new),
+            sender: Mutex::new(None),
+            state: watch::Sender::new(ClientState::Connecting { endpoint: endpoint.to_owned() }),
+            connected_at: Mutex::new(None),
+            events: broadcast::Sender::new(state::EVENTS_CAPACITY),
+            closed: watch::Sender::new(false)
+        }
+    }
+    fn is_connected(&self) -> bool
+    {
+        matches!(*self.state.borrow(), ClientState::Connected { .. })
+    }
+    fn set_state(&self, state: ClientState)
+    {
+        if let ClientState::Connected { .. } = state
+        {
+            *self.connected_at.lock().unwrap() = Some(Instant::now());
+        }
+        let _ = self.events.send(state.clone());
+        self.state.send_replace(state);
+    }
+    ///Канал отправки текущего соединения, если клиент подключен
+    fn sender(&self) -> Option<Outbound>
+    {
+        if !self.is_connected()
         {
-            let guard = IS_CONNECTED.get_or_init(|| Mutex::new(HashMap::new())).lock().await;
-            if let Some(curr) = guard.get(Self::get_id())
+            return None;
+        }
+        self.sender.lock().unwrap().clone()
+    }
+    ///Сколько продержалось последнее подключение, ноль если подключиться не удалось
+    fn uptime(&self) -> Duration
+    {
+        self.connected_at.lock().unwrap().take().map(|t| t.elapsed()).unwrap_or_default()
+    }
+    fn set_sender(&self, sender: Option<Outbound>)
+    {
+        *self.sender.lock().unwrap() = sender;
+    }
+    fn is_closed(&self) -> bool
+    {
+        *self.closed.borrow()
+    }
+    async fn wait_closed(&self)
+    {
+        let mut closed = self.closed.subscribe();
+        let _ = closed.wait_for(|c| *c).await;
+    }
+}
+
+///Запущенный экземпляр клиента, все копии работают с одним и тем же соединением
+/// несколько экземпляров (в том числе одного типа клиента) могут работать одновременно
+pub struct ClientHandle<T>
+{
+    connection: Arc<Connection>,
+    _message: PhantomData<fn(T)>
+}
+impl<T> Clone for ClientHandle<T>
+{
+    fn clone(&self) -> Self
+    {
+        Self::new(self.connection.clone())
+    }
+}
+impl<T> ClientHandle<T>
+{
+    fn new(connection: Arc<Connection>) -> Self
+    {
+        Self
+        {
+            connection,
+            _message: PhantomData
+        }
+    }
+    pub fn id(&self) -> &'static str
+    {
+        self.connection.id
+    }
+    pub fn is_connected(&self) -> bool
+    {
+        self.connection.is_connected()
+    }
+    ///Подписка на изменения состояния подключения
+    pub fn state(&self) -> watch::Receiver<ClientState>
+    {
+        self.connection.state.subscribe()
+    }
+    ///Поток состояний подключения, начиная с текущего
+    pub fn state_changes(&self) -> BoxStream<'static, ClientState>
+    {
+        let events = self.connection.events.subscribe();
+        state::changes(&self.connection.state.subscribe(), events)
+    }
+    pub async fn ping(&self)
+    {
+        if let Some(sender) = self.connection.sender()
+        {
+            let _ = sender.send_message(Message::Ping([12].to_vec()));
+        }
+        else
+        {
+            error!("Ошибка отправки сообщения, нет подключения к серверу");
+        }
+    }
+    ///Закрытие соединения без переподключения, завершается когда клиент остановлен
+    pub async fn close(&self)
+    {
+        self.connection.closed.send_replace(true);
+        if let Some(sender) = self.connection.sender()
+        {
+            let _ = sender.send_message(Message::Close(None));
+        }
+        let mut state = self.connection.state.subscribe();
+        let _ = state.wait_for(|s| *s == ClientState::Closed).await;
+    }
+    ///Отправка файла на сервер, при разрыве соединения отправка продолжается после переподключения
+    /// с последней подтвержденной сервером позиции
+    pub async fn send_file(&self, path: impl AsRef<Path>) -> Result<(), TransferError>
+    {
+        let path = path.as_ref();
+        let mut attempts = 0;
+        loop
+        {
+            let result = match self.connection.sender()
+            {
+                Some(sender) => files::send_file(&sender, path, &self.connection.files).await,
+                None => Err(TransferError::NotConnected)
+            };
+            match result
             {
-                if *curr == true
+                Err(TransferError::Interrupted) | Err(TransferError::NotConnected) if attempts < FILE_RESUME_ATTEMPTS && !self.connection.is_closed() =>
                 {
-                    let msg = Message::Ping([12].to_vec());
-                    let sender = SENDER.get().unwrap().lock().await;
-                    let curr_sender = sender.get(Self::get_id()).unwrap();
-                    let _ = curr_sender.unbounded_send(msg);
-                }
-                else
+                    attempts += 1;
+                    logger::warn!("Отправка файла {} прервана, попытка продолжить {} из {}", path.display(), attempts, FILE_RESUME_ATTEMPTS);
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                },
+                result => return result
+            }
+        }
+    }
+    ///Ожидание следующего файла принятого от сервера, файлы принимаются только если заданы настройки `files`,
+    /// после остановки клиента возвращает `None`
+    pub async fn receive_file(&self) -> Option<ReceivedFile>
+    {
+        self.connection.inbox.receive().await
+    }
+    ///Количество сообщений ожидающих подключения к серверу
+    pub async fn queued(&self) -> usize
+    {
+        match self.connection.outbox.as_ref()
+        {
+            Some(outbox) => outbox.lock().await.len(),
+            None => 0
+        }
+    }
+}
+impl<T> ClientHandle<T> where T: serde::Serialize
+{
+    ///Отправка сообщения, если клиент не подключен и задана очередь `outbox` то сообщение
+    /// будет отправлено после подключения
+    pub async fn send(&self, msg: T)
+    {
+        if let Err(e) = self.try_send(msg).await
+        {
+            logger::error!("Ошибка отправки сообщения {}", e);
+        }
+    }
+    ///Отправка сообщения, сообщение поставлено в очередь отправки соединения или в `outbox` если клиент не подключен
+    pub async fn try_send(&self, msg: T) -> Result<(), SendError>
+    {
+        let message = transport::serialize(&msg)?;
+        //пока очередь заблокирована она не может быть отправлена, поэтому сообщение не обгонит сообщения из очереди
+        let mut outbox = match self.connection.outbox.as_ref()
+        {
+            Some(outbox) => Some(outbox.lock().await),
+            None => None
+        };
+        let sender = self.connection.sender();
+        //пока очередь не отправлена полностью новые сообщения ставятся в ее конец
+        if let Some((sender, outbox)) = sender.as_ref().zip(outbox.as_mut()).filter(|(_, o)| !o.is_empty())
+        {
+            outbox.flush(sender).await;
+        }
+        if let Some(outbox) = outbox.as_mut().filter(|o| sender.is_none() || !o.is_empty())
+        {
+            outbox.push(message).await;
+            Ok(())
+        }
+        else if let Some(sender) = sender
+        {
+            sender.send(message)
+        }
+        else
+        {
+            Err(SendError::NotConnected)
+        }
+    }
+    ///Отправка сообщений через `Sink`, ошибки отправки возвращаются как в `try_send`
+    pub fn sink(&self) -> impl Sink<T, Error = SendError> + Send + Unpin where T: Send + 'static
+    {
+        Box::pin(sink::unfold(self.clone(), |handle, msg: T| async move
+        {
+            handle.try_send(msg).await?;
+            Ok(handle)
+        }))
+    }
+    ///Отправка серверу нового токена без переподключения, при следующих подключениях
+    /// токен по прежнему берется из `ClientConfig::handshake`
+    pub fn refresh_token(&self, token: impl Into<String>) -> Result<(), SendError>
+    {
+        let sender = self.connection.sender().ok_or(SendError::NotConnected)?;
+        sender.send_message(auth::encode(&AuthFrame::Token(token.into())))
+    }
+    ///Отправка сообщения, завершается когда сообщение записано в сокет,
+    /// если клиент не подключен то сообщение не ставится в `outbox`, возвращается `SendError::NotConnected`
+    pub async fn send_confirmed(&self, msg: T) -> Result<(), SendError>
+    {
+        let message = transport::serialize(&msg)?;
+        let sender = self.connection.sender().ok_or(SendError::NotConnected)?;
+        sender.send_confirmed(message).await
+    }
+}
+
+///Настройки соединения полученные из реализации трейта
+#[derive(Clone)]
+struct Settings
+{
+    batching: Option<BatchSettings>,
+    chunking: Option<ChunkSettings>,
+    on_progress: fn(ChunkProgress),
+    files: Option<FileSettings>,
+    config: ClientConfig
+}
+
+///Настройки экземпляра клиента __C__
+fn settings<C, T>(config: ClientConfig) -> Settings
+where C: Client<T> + ?Sized, T: serde::Serialize + Send + Sync, for <'de> T : serde::Deserialize<'de> + Sized + Send
+{
+    Settings
+    {
+        batching: C::batching(),
+        chunking: C::chunking(),
+        on_progress: C::on_chunk_progress,
+        files: C::files(),
+        config
+    }
+}
+
+///Запуск экземпляра клиента, возвращает клиента и канал с результатом первого подключения
+async fn launch<F, T, Fut>(cli_id: &'static str, addr: String, settings: Settings, f: F) -> (ClientHandle<T>, oneshot::Receiver<Result<HeaderMap, ConnectError>>)
+where T: serde::Serialize + Send + Sync + 'static, for <'de> T : serde::Deserialize<'de> + Sized + Send, F:  Send + Sync + Clone + 'static + Fn(T) -> Fut, Fut: Future<Output = ()> + Send + 'static
+{
+    let (handshake, result) = oneshot::channel();
+    let mut handshake = Some(handshake);
+    let outbox = match settings.config.outbox.clone()
+    {
+        Some(outbox) => Some(Outbox::open(outbox).await),
+        None => None
+    };
+    let mut endpoints = Endpoints::new(addr, settings.config.failover.clone());
+    let connection = Arc::new(Connection::new(cli_id, endpoints.current(), settings.files.clone().unwrap_or_default(), outbox));
+    CONNECTIONS.insert(cli_id, connection.clone());
+    let handle = ClientHandle::new(connection.clone());
+    tokio::spawn(async move
+    {
+        let policy = settings.config.reconnect;
+        //количество неудачных попыток подряд, сбрасывается если подключение продержалось достаточно долго
+        let mut attempt = 0;
+        while !connection.is_closed()
+        {
+            let primary = endpoints.primary_check().map(|interval| (endpoints.primary(), interval));
+            let reason = start(&connection, endpoints.current(), primary, &mut handshake, f.clone(), settings.clone()).await;
+            if connection.is_closed()
+            {
+                break;
+            }
+            match reason
+            {
+                DisconnectReason::ConnectFailed(e) =>
                 {
-                    error!("Ошибка отправки сообщения, нет подключения к серверу");
-                }
+                    attempt += 1;
+                    endpoints.failed();
+                    //первое подключение не удалось если ни к одному серверу подключиться не получилось
+                    if attempt as usize >= endpoints.len() || !policy.allows(attempt)
+                    {
+                        if let Some(handshake) = handshake.take()
+                        {
+                            let _ = handshake.send(Err(ConnectError::Failed(e)));
+                        }
+                    }
+                },
+                DisconnectReason::ServerClosed { .. } if !policy.reconnect_on_close => break,
+                //основной сервер уже проверен, подключаемся к нему без задержки
+                DisconnectReason::PrimaryRecovered =>
+                {
+                    attempt = 0;
+                    endpoints.reset();
+                    continue;
+                },
+                _ => attempt = policy.after_disconnect(attempt, connection.uptime())
             }
-            else 
+            if !policy.allows(attempt)
             {
-                error!("Ошибка отправки сообщения, нет подключения к серверу");
+                error!("Попытки подключения к серверу {} исчерпаны, клиент {} остановлен", endpoints.current(), connection.id);
+                break;
             }
+            let delay = policy.delay(attempt);
+            logger::warn!("Повторная попытка подключения к серверу {} через {:?}", endpoints.current(), delay);
+            connection.set_state(ClientState::Reconnecting { attempt, delay });
+            tokio::select!
+            {
+                _ = tokio::time::sleep(delay) => (),
+                _ = connection.wait_closed() => break
+            };
+        }
+        connection.closed.send_replace(true);
+        //экземпляр мог быть уже заменен новым запуском клиента с тем же id
+        CONNECTIONS.remove_if(cli_id, |_, c| Arc::ptr_eq(c, &connection));
+        connection.inbox.close();
+        connection.set_state(ClientState::Closed);
+    });
+    (handle, result)
+}
+
+///Вызов обработчика для принятых сообщений, при `Dispatch::Spawn` (__limit__ задан) каждое сообщение
+/// обрабатывается в отдельной задаче, но ожидается свободное место если задач уже слишком много,
+/// __busy__ отмечает для проверки соединения что пока вызов не завершен соединение не читается
+async fn dispatch<F, T, Fut>(f: F, messages: Vec<T>, limit: Option<Arc<Semaphore>>, busy: Busy<'_>) -> Result<(), tungstenite::Error>
+where T: Send + 'static, F: Send + Clone + 'static + Fn(T) -> Fut, Fut: Future<Output = ()> + Send + 'static
+{
+    let _busy = busy;
+    for message in messages
+    {
+        match limit.as_ref()
+        {
+            Some(limit) =>
+            {
+                let permit = limit.clone().acquire_owned().await;
+                let f = f.clone();
+                tokio::spawn(async move
+                {
+                    f(message).await;
+                    drop(permit);
+                });
+            },
+            None => f(message).await
         }
     }
+    Ok(())
 }
 
-async fn start<F, T>(cli_id: &str, addr: String, f:F, attempts: u8, delay: u64) -> bool 
-where T: serde::Serialize + Send, for <'de> T : serde::Deserialize<'de> + Sized + Send, F:  Send + Clone + 'static + Fn(T)
+///Чем завершилось ожидание остановки соединения
+enum Stop
 {
-    let (sender, local_receiver) = unbounded::<Message>();
-    if let Some(s) = SENDER.get()
+    ///клиент закрыт вызовом `close`
+    Closed,
+    ///сервер не ответил на ping
+    HeartbeatTimeout,
+    ///клиент подключен к резервному серверу, а основной снова доступен
+    PrimaryRecovered
+}
+
+///Подключение к серверу __addr__ (`ws://`, `wss://` или `unix:`) и рукопожатие websocket,
+/// __headers__ дополнительные заголовки запроса рукопожатия этой попытки подключения
+async fn open(addr: &str, config: &ClientConfig, headers: HeaderMap) -> Result<(WebSocketStream<Box<dyn Io>>, Response), tungstenite::Error>
+{
+    //параметры рукопожатия формируются заново при каждой попытке подключения
+    let handshake = config.handshake.as_ref().map(|h| h.build());
+    #[cfg(unix)]
+    if let Some((socket, path)) = unix::parse(addr)
     {
-        let mut guard = s.lock().await;
-        guard.insert(cli_id.to_owned(), sender.clone());
+        let mut request = format!("ws://localhost{}", path).into_client_request()?;
+        if let Some(handshake) = handshake.as_ref()
+        {
+            handshake.apply(&mut request)?;
+        }
+        request.headers_mut().extend(headers);
+        let stream = tokio::net::UnixStream::connect(socket).await?;
+        return client_async_with_config(request, Box::new(stream) as Box<dyn Io>, Some(config.websocket)).await;
+    }
+    let mut request = addr.into_client_request()?;
+    if let Some(handshake) = handshake.as_ref()
+    {
+        handshake.apply(&mut request)?;
+    }
+    request.headers_mut().extend(headers);
+    let secure = match request.uri().scheme_str()
+    {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => return Err(tungstenite::Error::Url(UrlError::UnsupportedUrlScheme))
+    };
+    let host = request.uri().host().ok_or(tungstenite::Error::Url(UrlError::NoHostName))?;
+    //адрес ipv6 указывается в квадратных скобках
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_owned();
+    let port = request.uri().port_u16().unwrap_or(if secure { 443 } else { 80 });
+    let tcp = TcpStream::connect((host.as_str(), port)).await?;
+    let stream: Box<dyn Io> = if secure
+    {
+        #[cfg(feature = "tls")]
+        match config.tls.as_ref()
+        {
+            Some(tls) => Box::new(tls.connect(&host, tcp).await?),
+            None => return Err(tungstenite::Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, "для подключения по wss:// необходимо задать ClientConfig::tls")))
+        }
+        #[cfg(not(feature = "tls"))]
+        return Err(tungstenite::Error::Url(UrlError::TlsFeatureNotEnabled));
     }
     else
     {
-        let mut hm: HashMap<String, UnboundedSender<Message>> = HashMap::new();
-        hm.insert(cli_id.to_owned(), sender.clone());
-        let _ = SENDER.set(Mutex::new(hm));
+        Box::new(tcp)
+    };
+    client_async_with_config(request, stream, Some(config.websocket)).await
+}
+
+///Ответ на запрос сервера прислать новый токен, токен берется из `ClientConfig::handshake`
+fn answer_auth_request(data: &[u8], sender: &Outbound, config: &ClientConfig)
+{
+    if auth::decode(data) != Some(AuthFrame::Request)
+    {
+        return;
+    }
+    match config.handshake.as_ref().and_then(|h| h.build().token().map(str::to_owned))
+    {
+        Some(token) =>
+        {
+            logger::info!("Сервер запросил новый токен");
+            let _ = sender.send_message(auth::encode(&AuthFrame::Token(token)));
+        },
+        None => logger::warn!("Сервер запросил новый токен, но ClientConfig::handshake не задает токен (`Handshake::bearer`)")
+    }
+}
+
+///Периодическая проверка что к основному серверу __primary__ можно подключиться,
+/// завершается после первого успешного подключения, если проверка не нужна то никогда не завершается
+async fn wait_primary(primary: Option<(&str, Duration)>, config: &ClientConfig)
+{
+    let Some((addr, interval)) = primary else
+    {
+        return future::pending().await;
+    };
+    loop
+    {
+        tokio::time::sleep(interval).await;
+        //сервер с подписью фреймов принимает только подключения с открытым ключом клиента
+        #[cfg(feature = "seal")]
+        let headers = config.sealing.as_ref().map(|s| s.offer().1).unwrap_or_default();
+        #[cfg(not(feature = "seal"))]
+        let headers = HeaderMap::new();
+        if let Ok((mut ws, _)) = open(addr, config, headers).await
+        {
+            let _ = ws.close(None).await;
+            logger::info!("Основной сервер {} снова доступен", addr);
+            return;
+        }
     }
-    let connected = retry(attempts, delay, || connect_async(&addr)).await;
+}
+
+///Одно подключение к серверу __addr__, __primary__ - адрес основного сервера и интервал проверки его доступности
+/// __handshake__ канал для результата первого подключения, забирается после успешного рукопожатия
+async fn start<F, T, Fut>(connection: &Connection, addr: &str, primary: Option<(&str, Duration)>, handshake: &mut Option<oneshot::Sender<Result<HeaderMap, ConnectError>>>, f:F, settings: Settings) -> DisconnectReason
+where T: serde::Serialize + Send + 'static, for <'de> T : serde::Deserialize<'de> + Sized + Send, F:  Send + Sync + Clone + 'static + Fn(T) -> Fut, Fut: Future<Output = ()> + Send + 'static
+{
+    connection.set_state(ClientState::Connecting { endpoint: addr.to_owned() });
+    let (sender, local_receiver) = Outbound::new(settings.batching, settings.chunking, settings.config.send_queue_limit, settings.on_progress);
+    //для обмена ключами шифрования фреймов к запросу добавляется открытый ключ клиента
+    #[cfg(feature = "seal")]
+    let (offer, headers) = settings.config.sealing.as_ref().map(|s| s.offer()).unzip();
+    #[cfg(feature = "seal")]
+    let headers = headers.unwrap_or_default();
+    #[cfg(not(feature = "seal"))]
+    let headers = HeaderMap::new();
+    let connected = tokio::select!
+    {
+        connected = open(addr, &settings.config, headers) => connected,
+        _ = connection.wait_closed() => return DisconnectReason::ClientClosed
+    };
     if let Err(e) = connected.as_ref()
     {
-        error!("Ошибка подключения к серверу websocket по адресу {} -> {}", &addr, e.to_string());
-        return false;
+        error!("Ошибка подключения к серверу websocket по адресу {} -> {}", addr, e.to_string());
+        let reason = DisconnectReason::ConnectFailed(e.to_string());
+        connection.set_state(ClientState::Disconnected(reason.clone()));
+        return reason;
     }
-    let mut conn = IS_CONNECTED.get_or_init(|| Mutex::new(HashMap::new())).lock().await;
-    conn.insert(cli_id.to_owned(), true);
-    drop(conn);
+    #[cfg(feature = "seal")]
+    let (sealer, mut opener) = match settings.config.sealing.as_ref().zip(offer).map(|(s, o)| o.finish(s, connected.as_ref().unwrap().1.headers()))
+    {
+        Some(Ok((sealer, opener))) => (Some(sealer), Some(opener)),
+        Some(Err(e)) =>
+        {
+            error!("Ошибка подключения к серверу websocket по адресу {} -> {}", addr, e);
+            let reason = DisconnectReason::ConnectFailed(e.to_string());
+            connection.set_state(ClientState::Disconnected(reason.clone()));
+            return reason;
+        },
+        None => (None, None)
+    };
+    //сообщения накопленные в очереди отправляются первыми, до того как соединение станет доступно для отправки
+    let mut outbox = match connection.outbox.as_ref()
+    {
+        Some(outbox) => Some(outbox.lock().await),
+        None => None
+    };
+    if let Some(outbox) = outbox.as_mut()
+    {
+        let sent = outbox.flush(&sender).await;
+        if sent > 0
+        {
+            logger::info!("Отправлено {} сообщений из очереди", sent);
+        }
+    }
+    connection.set_sender(Some(sender.clone()));
+    connection.set_state(ClientState::Connected { endpoint: addr.to_owned() });
+    drop(outbox);
     let (ws_stream, resp) = connected.unwrap();
     logger::debug!("Рукопожатие с сервером успешно");
     for h in resp.headers()
     {
-        logger::debug!("* {}: {}", h.0.as_str(), h.1.to_str().unwrap());
+        logger::debug!("* {}: {}", h.0.as_str(), h.1.to_str().unwrap_or_default());
+    }
+    if let Some(handshake) = handshake.take()
+    {
+        let _ = handshake.send(Ok(resp.headers().clone()));
     }
     let (write, read) = ws_stream.split();
     //сообщения полученные по каналу local_receiver'ом форвардятся прямо в вебсокет
-    let send_to_ws = local_receiver.map(Ok).forward(write);
+    let writer = Writer::new(write);
+    #[cfg(feature = "seal")]
+    let writer = writer.sealed(sealer);
+    let send_to_ws = local_receiver.map(Ok).forward(writer);
     let fun = f.clone();
+    let files = files::spawn_handler(sender.clone(), settings.files.clone(), connection.inbox.clone(), None);
+    let mut inbound = Inbound::new(settings.chunking, settings.config.websocket.max_message_size, settings.on_progress, files);
+    let server_closed = Mutex::new(None);
+    let limit = match settings.config.dispatch
+    {
+        Dispatch::Inline => None,
+        Dispatch::Spawn { max_concurrent } => Some(Arc::new(Semaphore::new(max_concurrent.max(1))))
+    };
+    let heartbeat = Heartbeat::new(settings.config.heartbeat);
     //для каждого входяшего сообщения по вебсокет производим обработку
-    let from_ws = 
+    let from_ws =
     {
-        read.for_each(|message| async
+        read.try_for_each(|message|
         {
-            if let Ok(message) = message
+            heartbeat.received();
+            let mut messages = Vec::new();
+            //фрейм сервера не прошедший проверку подписи отбрасывается и соединение закрывается
+            #[cfg(feature = "seal")]
+            let message = match (opener.as_mut(), message)
             {
-                if message.is_binary()
+                (Some(opener), message @ (Message::Binary(_) | Message::Text(_))) => match opener.open(&message.into_data())
                 {
-                    let msg = serde_json::from_slice::<T>(&message.into_data()).with_context(|| format!("Данный объект отличается от того который вы хотите получить"));
-                    if let Ok(m) = msg
+                    Ok(data) => Message::Binary(data),
+                    Err(e) =>
                     {
-                        let fun = fun.clone();
-                        fun(m)
+                        logger::error!("Фрейм сервера не прошел проверку: {}", e);
+                        if server_closed.lock().unwrap().replace(DisconnectReason::Error(e.to_string())).is_none()
+                        {
+                            let frame = CloseFrame { code: CloseCode::Policy, reason: e.to_string().into() };
+                            let _ = sender.send_message(Message::Close(Some(frame)));
+                        }
+                        return dispatch(fun.clone(), messages, limit.clone(), heartbeat.busy());
                     }
-                    else 
+                },
+                (_, message) => message
+            };
+            if matches!(&message, Message::Binary(data) if auth::is_auth_frame(data))
+            {
+                answer_auth_request(&message.into_data(), &sender, &settings.config);
+            }
+            else if message.is_binary()
+            {
+                match inbound.payloads(message.into_data())
+                {
+                    Ok(payloads) => for payload in payloads
                     {
-                        logger::error!("Ошибка десериализации объекта на клиенте: {}", msg.err().unwrap());
-                    }
+                        let msg = serde_json::from_slice::<T>(&payload).context("Данный объект отличается от того который вы хотите получить");
+                        if let Ok(m) = msg
+                        {
+                            messages.push(m);
+                        }
+                        else
+                        {
+                            logger::error!("Ошибка десериализации объекта на клиенте: {}", msg.err().unwrap());
+                        }
+                    },
+                    Err(e) => logger::error!("Ошибка распаковки сообщения на клиенте: {}", e)
                 }
-                //logger::info!("получено сообщение от сервера {:?}", message);
             }
-            else
+            else if let Message::Close(frame) = &message
             {
-                logger::error!("Ошибка чтения сообщения! {} -> {}", message.err().unwrap().to_string(), backtrace!());
+                let reason = match frame
+                {
+                    Some(frame) =>
+                    {
+                        logger::warn!("Сервер закрыл соединение с кодом {}: {}", frame.code, &frame.reason);
+                        DisconnectReason::ServerClosed { code: frame.code.into(), reason: frame.reason.to_string() }
+                    },
+                    None => DisconnectReason::ServerClosed { code: CloseCode::Status.into(), reason: String::new() }
+                };
+                //причина закрытия из-за фрейма не прошедшего проверку не заменяется ответным фреймом закрытия сервера
+                server_closed.lock().unwrap().get_or_insert(reason);
             }
+            //logger::info!("получено сообщение от сервера {:?}", message);
+            dispatch(fun.clone(), messages, limit.clone(), heartbeat.busy())
         })
     };
-    pin_mut!(send_to_ws, from_ws);
-    future::select(send_to_ws, from_ws).await;
-    let mut conn = IS_CONNECTED.get().unwrap().lock().await;
-    conn.remove(cli_id);
-    let mut snd = SENDER.get().unwrap().lock().await;
-    snd.remove(cli_id);
-    logger::warn!("Сервер недоступен! повторная попытка подключения");
-    return false;
+    //после вызова close ждем ответного фрейма закрытия от сервера, но не дольше CLOSE_TIMEOUT
+    //соединение так же разрывается если сервер перестал отвечать на ping или основной сервер снова доступен
+    let stop = async
+    {
+        tokio::select!
+        {
+            _ = async
+            {
+                connection.wait_closed().await;
+                tokio::time::sleep(CLOSE_TIMEOUT).await;
+            } => Stop::Closed,
+            _ = heartbeat.run(&sender) => Stop::HeartbeatTimeout,
+            _ = wait_primary(primary, &settings.config) => Stop::PrimaryRecovered
+        }
+    };
+    pin_mut!(send_to_ws, from_ws, stop);
+    let mut stopped = Stop::Closed;
+    let error = match future::select(future::select(send_to_ws, from_ws), stop).await
+    {
+        Either::Right((Stop::PrimaryRecovered, rest)) =>
+        {
+            //резервный сервер закрывает соединение в ответ, но ждем не дольше CLOSE_TIMEOUT
+            let frame = CloseFrame { code: CloseCode::Away, reason: "переключение на основной сервер".into() };
+            if sender.send_message(Message::Close(Some(frame))).is_ok()
+            {
+                let _ = tokio::time::timeout(CLOSE_TIMEOUT, rest).await;
+            }
+            stopped = Stop::PrimaryRecovered;
+            None
+        },
+        Either::Right((stop, _)) =>
+        {
+            stopped = stop;
+            None
+        },
+        Either::Left((Either::Right((Err(e), send_to_ws)), _)) =>
+        {
+            logger::error!("Ошибка чтения сообщения! {} -> {}", e.to_string(), backtrace!());
+            transport::close_with_error(&e, &sender, send_to_ws).await;
+            Some(e.to_string())
+        },
+        Either::Left((Either::Left((Err(e), _)), _)) => Some(e.to_string()),
+        _ => None
+    };
+    connection.set_sender(None);
+    let reason = if connection.is_closed()
+    {
+        DisconnectReason::ClientClosed
+    }
+    else if let Stop::HeartbeatTimeout = stopped
+    {
+        logger::warn!("Сервер не ответил на ping, соединение разорвано");
+        DisconnectReason::HeartbeatTimeout
+    }
+    else if let Stop::PrimaryRecovered = stopped
+    {
+        logger::info!("Отключение от резервного сервера {}", addr);
+        DisconnectReason::PrimaryRecovered
+    }
+    else if let Some(reason) = server_closed.into_inner().unwrap()
+    {
+        reason
+    }
+    else
+    {
+        logger::warn!("Сервер недоступен!");
+        DisconnectReason::Error(error.unwrap_or_else(|| "соединение разорвано".to_owned()))
+    };
+    connection.set_state(ClientState::Disconnected(reason.clone()));
+    reason
 }
 
 #[cfg(test)]
 mod test
 {
-    
-}
\ No newline at end of file
+    use super::{launch, Settings, CONNECTIONS};
+    use crate::ClientConfig;
+
+    #[tokio::test]
+    async fn test_connection_removed()
+    {
+        let settings = Settings { batching: None, chunking: None, on_progress: |_| (), files: None, config: ClientConfig::default() };
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (first, _) = launch("test_connection_removed", format!("ws://{}/", addr), settings.clone(), |_: String| async {}).await;
+        let (second, _) = launch("test_connection_removed", format!("ws://{}/", addr), settings, |_: String| async {}).await;
+        //остановка предыдущего экземпляра не удаляет новый
+        first.close().await;
+        assert!(CONNECTIONS.contains_key("test_connection_removed"));
+        second.close().await;
+        assert!(!CONNECTIONS.contains_key("test_connection_removed"));
+        //очередь принятых файлов остановленного клиента закрыта
+        assert!(tokio::time::timeout(std::time::Duration::from_secs(1), second.receive_file()).await.unwrap().is_none());
+    }
+}
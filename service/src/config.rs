@@ -0,0 +1,86 @@
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use crate::{HeartbeatSettings, ReconnectPolicy};
+
+///Настройки сервера
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig
+{
+    ///ограничения websocket: максимальный размер сообщения и фрейма, размеры буфера записи, прием фреймов без маски  
+    ///сообщения больше допустимого размера отклоняются, соединение закрывается с кодом 1009
+    pub websocket: WebSocketConfig,
+    ///максимальное количество сообщений ожидающих отправки в одно соединение,
+    ///при превышении отправка возвращает `SendError::QueueFull`, по умолчанию не ограничено
+    pub send_queue_limit: Option<usize>,
+    ///автоматическая отправка ping клиентам, клиент не ответивший вовремя отключается
+    pub heartbeat: Option<HeartbeatSettings>,
+    ///если задан то подключения с источников (заголовок `Origin`) не входящих в список
+    ///отклоняются при рукопожатии с кодом 403, по умолчанию источник не проверяется
+    #[cfg(feature = "server")]
+    pub origins: Option<crate::OriginPolicy>,
+    ///ограничения скорости входящих сообщений и количества подключений с одного ip, по умолчанию не ограничено
+    #[cfg(feature = "server")]
+    pub limits: Option<crate::RateLimits>,
+    ///разрешенные и запрещенные подсети, подключения с других адресов закрываются сразу после приема,
+    ///списки можно менять во время работы сервера через копию фильтра
+    #[cfg(feature = "server")]
+    pub ip_filter: Option<crate::IpFilter>,
+    ///если задан то подключение без действующего JWT отклоняется при рукопожатии с кодом 401
+    #[cfg(all(feature = "jwt", feature = "server"))]
+    pub auth: Option<crate::JwtAuth>,
+    ///подпись и шифрование фреймов, клиенты должны подключаться с совместимыми настройками
+    #[cfg(feature = "seal")]
+    pub sealing: Option<crate::Sealing>,
+    ///если задан то сервер принимает только подключения по TLS (`wss://`)
+    #[cfg(all(feature = "tls", feature = "server"))]
+    pub tls: Option<crate::ServerTls>
+}
+
+///Настройки клиента
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig
+{
+    ///ограничения websocket: максимальный размер сообщения и фрейма, размеры буфера записи  
+    ///сообщения больше допустимого размера отклоняются, соединение закрывается с кодом 1009
+    pub websocket: WebSocketConfig,
+    ///задержки между попытками подключения после ошибки или разрыва соединения
+    pub reconnect: ReconnectPolicy,
+    ///очередь сообщений отправленных пока клиент не подключен, по умолчанию такие сообщения теряются
+    #[cfg(feature = "client")]
+    pub outbox: Option<crate::OutboxSettings>,
+    ///максимальное количество сообщений ожидающих отправки в соединение,
+    ///при превышении отправка возвращает `SendError::QueueFull`, по умолчанию не ограничено
+    pub send_queue_limit: Option<usize>,
+    ///как вызывается обработчик входящих сообщений
+    pub dispatch: Dispatch,
+    ///автоматическая отправка ping серверу, если сервер не ответил вовремя то соединение
+    ///разрывается с причиной `DisconnectReason::HeartbeatTimeout` и клиент переподключается
+    pub heartbeat: Option<HeartbeatSettings>,
+    ///резервные серверы и порядок переключения между ними, по умолчанию клиент подключается только к одному серверу
+    #[cfg(feature = "client")]
+    pub failover: crate::FailoverSettings,
+    ///подпись и шифрование фреймов, настройки должны быть совместимы с настройками сервера
+    #[cfg(feature = "seal")]
+    pub sealing: Option<crate::Sealing>,
+    ///настройки подключения по `wss://`
+    #[cfg(all(feature = "tls", feature = "client"))]
+    pub tls: Option<crate::ClientTls>,
+    ///дополнительные заголовки, параметры и cookie запроса рукопожатия, формируются перед каждой попыткой подключения
+    #[cfg(feature = "client")]
+    pub handshake: Option<crate::HandshakeBuilder>
+}
+
+///Вызов обработчика входящих сообщений клиента
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dispatch
+{
+    ///обработчик вызывается в цикле чтения и следующее сообщение не читается пока обработчик не завершится,
+    ///сообщения обрабатываются строго по порядку
+    #[default]
+    Inline,
+    ///каждое сообщение обрабатывается в отдельной задаче, одновременно не больше __max_concurrent__ задач,
+    ///при достижении лимита чтение новых сообщений приостанавливается
+    Spawn
+    {
+        max_concurrent: usize
+    }
+}
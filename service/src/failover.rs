@@ -0,0 +1,155 @@
+use std::{sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use rand::Rng;
+
+///Порядок выбора сервера из списка
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailoverStrategy
+{
+    ///всегда начинать с основного (первого) сервера, при ошибке подключения переходить к следующему по списку,
+    ///пока подключение к резервному серверу работает клиент периодически проверяет доступность основного и возвращается на него
+    #[default]
+    Priority,
+    ///экземпляры клиента начинают с разных серверов по кругу, при ошибке подключения переход к следующему по списку
+    RoundRobin,
+    ///сервер выбирается случайно, при ошибке подключения выбирается другой случайный сервер
+    Random
+}
+
+///Настройки переключения между серверами
+/// __backups__ резервные адреса в порядке приоритета, адрес переданный в `start_client` считается основным
+/// __strategy__ порядок выбора сервера
+/// __primary_check__ как часто проверять доступность основного сервера пока клиент подключен к резервному (только для `Priority`)
+#[derive(Debug, Clone)]
+pub struct FailoverSettings
+{
+    pub backups: Vec<String>,
+    pub strategy: FailoverStrategy,
+    pub primary_check: Duration
+}
+impl Default for FailoverSettings
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            backups: Vec::new(),
+            strategy: FailoverStrategy::default(),
+            primary_check: Duration::from_secs(30)
+        }
+    }
+}
+impl FailoverSettings
+{
+    ///Резервные серверы __backups__ с выбором по приоритету
+    pub fn new<I, S>(backups: I) -> Self where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        Self
+        {
+            backups: backups.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+///следующий сервер для стратегии `RoundRobin`, общий для всех экземпляров клиента
+static ROUND_ROBIN: AtomicUsize = AtomicUsize::new(0);
+
+///Список серверов клиента и текущий выбранный сервер
+/// сервер меняется только после ошибки подключения, после разрыва работавшего соединения
+/// клиент сначала пробует переподключиться к тому же серверу
+pub(crate) struct Endpoints
+{
+    list: Vec<String>,
+    strategy: FailoverStrategy,
+    primary_check: Duration,
+    current: usize
+}
+impl Endpoints
+{
+    pub fn new(primary: String, settings: FailoverSettings) -> Self
+    {
+        let mut list = vec![primary];
+        list.extend(settings.backups);
+        let current = match settings.strategy
+        {
+            FailoverStrategy::Priority => 0,
+            FailoverStrategy::RoundRobin => ROUND_ROBIN.fetch_add(1, Ordering::Relaxed) % list.len(),
+            FailoverStrategy::Random => rand::thread_rng().gen_range(0..list.len())
+        };
+        Self
+        {
+            list,
+            strategy: settings.strategy,
+            primary_check: settings.primary_check,
+            current
+        }
+    }
+    pub fn current(&self) -> &str
+    {
+        &self.list[self.current]
+    }
+    pub fn len(&self) -> usize
+    {
+        self.list.len()
+    }
+    pub fn primary(&self) -> &str
+    {
+        &self.list[0]
+    }
+    ///Ошибка подключения к текущему серверу, выбор следующего
+    pub fn failed(&mut self)
+    {
+        let len = self.list.len();
+        self.current = match self.strategy
+        {
+            FailoverStrategy::Random if len > 1 => (self.current + rand::thread_rng().gen_range(1..len)) % len,
+            _ => (self.current + 1) % len
+        };
+    }
+    ///Возврат на основной сервер
+    pub fn reset(&mut self)
+    {
+        self.current = 0;
+    }
+    ///Если клиент подключен к резервному серверу и нужно проверять основной, то интервал проверки
+    pub fn primary_check(&self) -> Option<Duration>
+    {
+        (self.strategy == FailoverStrategy::Priority && self.current != 0).then_some(self.primary_check)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{Endpoints, FailoverSettings, FailoverStrategy};
+
+    #[test]
+    fn test_endpoints()
+    {
+        let mut endpoints = Endpoints::new("ws://a".to_owned(), FailoverSettings::new(["ws://b", "ws://c"]));
+        assert_eq!(endpoints.current(), "ws://a");
+        assert!(endpoints.primary_check().is_none());
+        endpoints.failed();
+        assert_eq!(endpoints.current(), "ws://b");
+        assert!(endpoints.primary_check().is_some());
+        endpoints.failed();
+        endpoints.failed();
+        assert_eq!(endpoints.current(), "ws://a");
+        endpoints.failed();
+        endpoints.reset();
+        assert_eq!(endpoints.current(), "ws://a");
+        //случайный выбор никогда не повторяет сервер с которым не удалось соединиться
+        let mut endpoints = Endpoints::new("ws://a".to_owned(), FailoverSettings { strategy: FailoverStrategy::Random, ..FailoverSettings::new(["ws://b"]) });
+        for _ in 0..10
+        {
+            let before = endpoints.current().to_owned();
+            endpoints.failed();
+            assert_ne!(endpoints.current(), before);
+            assert!(endpoints.primary_check().is_none());
+        }
+        //без резервных серверов всегда используется основной
+        let mut endpoints = Endpoints::new("ws://a".to_owned(), FailoverSettings { strategy: FailoverStrategy::Random, ..Default::default() });
+        endpoints.failed();
+        assert_eq!(endpoints.current(), "ws://a");
+    }
+}
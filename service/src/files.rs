@@ -0,0 +1,555 @@
+use std::{collections::HashMap, fmt::Display, io::SeekFrom, net::SocketAddr, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+use anyhow::anyhow;
+use futures::StreamExt;
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use logger::{debug, error};
+use sha2::{Digest, Sha256};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}};
+use tokio_tungstenite::tungstenite::Message;
+use crate::transport::Outbound;
+
+///Первый байт служебного фрейма передачи файлов, json не может начинаться с этого байта
+const FILE_MARKER: u8 = 0xF1;
+
+///Настройки передачи файлов
+/// __dir__ директория в которую сохраняются принятые файлы
+/// __block_size__ размер блока данных при отправке
+/// __window__ сколько блоков может быть отправлено без подтверждения
+#[derive(Debug, Clone)]
+pub struct FileSettings
+{
+    pub dir: PathBuf,
+    pub block_size: usize,
+    pub window: usize
+}
+impl FileSettings
+{
+    pub fn new(dir: impl Into<PathBuf>) -> Self
+    {
+        Self
+        {
+            dir: dir.into(),
+            ..Default::default()
+        }
+    }
+}
+impl Default for FileSettings
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            dir: PathBuf::from("files"),
+            block_size: 128 * 1024,
+            window: 8
+        }
+    }
+}
+
+///Принятый и проверенный файл
+#[derive(Debug, Clone)]
+pub struct ReceivedFile
+{
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    ///sha256 в hex
+    pub hash: String,
+    ///адрес отправителя, для клиента всегда None
+    pub peer: Option<SocketAddr>
+}
+
+#[derive(Debug)]
+pub enum TransferError
+{
+    Io(std::io::Error),
+    ///нет подключения к получателю
+    NotConnected,
+    ///получатель отказался принимать файл
+    Rejected(String),
+    ///соединение разорвано во время передачи
+    Interrupted,
+    ///хэш принятого файла не совпал с хэшем отправленного
+    Corrupted
+}
+impl Display for TransferError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            TransferError::Io(e) => write!(f, "Ошибка чтения файла: {}", e),
+            TransferError::NotConnected => f.write_str("Нет подключения для отправки файла"),
+            TransferError::Rejected(r) => write!(f, "Получатель отказался принимать файл: {}", r),
+            TransferError::Interrupted => f.write_str("Соединение разорвано во время передачи файла"),
+            TransferError::Corrupted => f.write_str("Хэш принятого файла не совпадает с отправленным")
+        }
+    }
+}
+impl std::error::Error for TransferError {}
+impl From<std::io::Error> for TransferError
+{
+    fn from(value: std::io::Error) -> Self
+    {
+        TransferError::Io(value)
+    }
+}
+
+///Служебные сообщения протокола передачи файлов, после заголовка фрейма могут идти данные файла
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub(crate) enum FileFrame
+{
+    Offer { id: String, name: String, size: u64, hash: String },
+    Accept { id: String, offset: u64 },
+    Reject { id: String, reason: String },
+    Data { id: String, offset: u64 },
+    Ack { id: String, offset: u64 },
+    Complete { id: String },
+    Verified { id: String, ok: bool }
+}
+impl FileFrame
+{
+    fn id(&self) -> &str
+    {
+        match self
+        {
+            FileFrame::Offer { id, .. }
+            | FileFrame::Accept { id, .. }
+            | FileFrame::Reject { id, .. }
+            | FileFrame::Data { id, .. }
+            | FileFrame::Ack { id, .. }
+            | FileFrame::Complete { id }
+            | FileFrame::Verified { id, .. } => id
+        }
+    }
+}
+
+///Отправки файлов ожидающие ответа получателя, у каждого соединения свой список
+pub(crate) type Transfers = Arc<Mutex<HashMap<String, UnboundedSender<FileFrame>>>>;
+
+pub(crate) fn is_file_frame(data: &[u8]) -> bool
+{
+    data.first() == Some(&FILE_MARKER)
+}
+
+///формат: маркер, длина заголовка (u32 be), заголовок в json, данные
+fn encode(frame: &FileFrame, data: &[u8]) -> Message
+{
+    let header = serde_json::to_vec(frame).unwrap_or_default();
+    let mut message = Vec::with_capacity(5 + header.len() + data.len());
+    message.push(FILE_MARKER);
+    message.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    message.extend_from_slice(&header);
+    message.extend_from_slice(data);
+    Message::Binary(message)
+}
+fn decode(message: &[u8]) -> anyhow::Result<(FileFrame, &[u8])>
+{
+    if message.len() < 5 || !is_file_frame(message)
+    {
+        return Err(anyhow!("Фрейм передачи файла поврежден"));
+    }
+    let len = u32::from_be_bytes(message[1..5].try_into()?) as usize;
+    if message.len() < 5 + len
+    {
+        return Err(anyhow!("Фрейм передачи файла поврежден"));
+    }
+    let frame = serde_json::from_slice::<FileFrame>(&message[5..5 + len])?;
+    Ok((frame, &message[5 + len..]))
+}
+
+///Входящие файлы ожидающие получения через `receive_file`, у каждого клиента и у каждого сервера своя очередь
+pub(crate) struct Inbox
+{
+    sender: UnboundedSender<ReceivedFile>,
+    receiver: tokio::sync::Mutex<UnboundedReceiver<ReceivedFile>>
+}
+impl Inbox
+{
+    pub fn new() -> Self
+    {
+        let (sender, receiver) = unbounded();
+        Self
+        {
+            sender,
+            receiver: tokio::sync::Mutex::new(receiver)
+        }
+    }
+    ///Ожидание следующего принятого файла, `None` если очередь закрыта и уже принятые файлы получены
+    pub async fn receive(&self) -> Option<ReceivedFile>
+    {
+        self.receiver.lock().await.next().await
+    }
+    ///Закрытие очереди, новые файлы в нее не попадают
+    #[cfg(feature = "client")]
+    pub fn close(&self)
+    {
+        self.sender.close_channel();
+    }
+}
+
+///Обработчик фреймов передачи файлов для одного соединения, возвращает канал в который надо передавать входящие фреймы
+/// __settings__ если не указаны то входящие файлы не принимаются, отправка файлов при этом работает
+pub(crate) fn spawn_handler(outbound: Outbound, settings: Option<FileSettings>, inbox: Arc<Inbox>, peer: Option<SocketAddr>) -> UnboundedSender<Vec<u8>>
+{
+    let (sender, mut receiver) = unbounded::<Vec<u8>>();
+    tokio::spawn(async move
+    {
+        let mut incoming: HashMap<String, Incoming> = HashMap::new();
+        while let Some(message) = receiver.next().await
+        {
+            let decoded = decode(&message);
+            if let Err(e) = decoded.as_ref()
+            {
+                error!("{}", e);
+                continue;
+            }
+            let (frame, data) = decoded.unwrap();
+            match frame
+            {
+                FileFrame::Accept { .. } | FileFrame::Reject { .. } | FileFrame::Ack { .. } | FileFrame::Verified { .. } =>
+                {
+                    let transfers = outbound.transfers().lock().unwrap();
+                    if let Some(transfer) = transfers.get(frame.id())
+                    {
+                        let _ = transfer.unbounded_send(frame);
+                    }
+                },
+                _ =>
+                {
+                    let reply = match receive_frame(&mut incoming, settings.as_ref(), frame, data, peer, &inbox).await
+                    {
+                        Ok(r) => r,
+                        Err((id, e)) =>
+                        {
+                            error!("Ошибка приема файла {} -> {}", &id, e);
+                            incoming.remove(&id);
+                            Some(FileFrame::Reject { id, reason: e.to_string() })
+                        }
+                    };
+                    if let Some(reply) = reply
+                    {
+                        let _ = outbound.send_bulk(encode(&reply, &[]));
+                    }
+                }
+            }
+        }
+        //соединение закрыто, ожидающие отправки получат ошибку Interrupted,
+        //недокачанные файлы остаются на диске для продолжения после переподключения
+        outbound.transfers().lock().unwrap().clear();
+    });
+    sender
+}
+
+///Принимаемый файл
+struct Incoming
+{
+    name: String,
+    size: u64,
+    hash: String,
+    file: File,
+    offset: u64
+}
+
+async fn receive_frame(incoming: &mut HashMap<String, Incoming>, settings: Option<&FileSettings>, frame: FileFrame, data: &[u8], peer: Option<SocketAddr>, inbox: &Inbox) -> Result<Option<FileFrame>, (String, anyhow::Error)>
+{
+    let id = frame.id().to_owned();
+    let err = |e: std::io::Error| (id.clone(), anyhow::Error::from(e));
+    match frame
+    {
+        FileFrame::Offer { id, name, size, hash } =>
+        {
+            let settings = settings.ok_or_else(|| (id.clone(), anyhow!("прием файлов отключен")))?;
+            //id используется в имени файла на диске, поэтому принимается только sha256 самого файла
+            if id != hash
+            {
+                return Err((id, anyhow!("id файла не совпадает с его хэшем")));
+            }
+            let part = part_path(&settings.dir, &id, peer).map_err(|e| (id.clone(), e))?;
+            tokio::fs::create_dir_all(&settings.dir).await.map_err(err)?;
+            let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(part).await.map_err(err)?;
+            let mut offset = file.metadata().await.map_err(err)?.len();
+            if offset > size
+            {
+                file.set_len(0).await.map_err(err)?;
+                offset = 0;
+            }
+            file.seek(SeekFrom::Start(offset)).await.map_err(err)?;
+            debug!("Прием файла {} ({} байт) с позиции {}", &name, size, offset);
+            incoming.insert(id.clone(), Incoming { name, size, hash, file, offset });
+            Ok(Some(FileFrame::Accept { id, offset }))
+        },
+        FileFrame::Data { id, offset } =>
+        {
+            let current = incoming.get_mut(&id).ok_or_else(|| (id.clone(), anyhow!("получены данные неизвестного файла")))?;
+            if current.offset != offset || offset + data.len() as u64 > current.size
+            {
+                return Err((id, anyhow!("нарушен порядок блоков файла")));
+            }
+            current.file.write_all(data).await.map_err(err)?;
+            current.file.flush().await.map_err(err)?;
+            current.offset += data.len() as u64;
+            Ok(Some(FileFrame::Ack { id, offset: current.offset }))
+        },
+        FileFrame::Complete { id } =>
+        {
+            let settings = settings.ok_or_else(|| (id.clone(), anyhow!("прием файлов отключен")))?;
+            let current = incoming.remove(&id).ok_or_else(|| (id.clone(), anyhow!("завершение неизвестного файла")))?;
+            current.file.sync_all().await.map_err(err)?;
+            drop(current.file);
+            let part = part_path(&settings.dir, &id, peer).map_err(|e| (id.clone(), e))?;
+            let (size, hash) = file_hash(&part).await.map_err(err)?;
+            if size != current.size || hash != current.hash
+            {
+                error!("Файл {} поврежден при передаче", &current.name);
+                let _ = tokio::fs::remove_file(&part).await;
+                return Ok(Some(FileFrame::Verified { id, ok: false }));
+            }
+            let path = reserve(&settings.dir, &safe_name(&current.name, &id)).await.map_err(err)?;
+            tokio::fs::rename(&part, &path).await.map_err(err)?;
+            let _ = inbox.sender.unbounded_send(ReceivedFile { name: current.name, path, size, hash, peer });
+            Ok(Some(FileFrame::Verified { id, ok: true }))
+        },
+        _ => Ok(None)
+    }
+}
+
+///Путь недокачанного файла в директории __dir__, __id__ должен быть sha256 в hex,
+/// иначе отправитель мог бы создать или обрезать файл за пределами директории  
+///у каждого ip отправителя свой файл, поэтому одинаковые файлы от разных отправителей не пишутся в один файл,
+/// а после переподключения с другого порта прием продолжается
+fn part_path(dir: &Path, id: &str, peer: Option<SocketAddr>) -> anyhow::Result<PathBuf>
+{
+    if id.len() != 64 || !id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return Err(anyhow!("недопустимый id файла {}", id));
+    }
+    let path = match peer
+    {
+        Some(peer) => dir.join(format!("{}.{}.part", id, peer.ip().to_canonical().to_string().replace(':', "-"))),
+        None => dir.join([id, ".part"].concat())
+    };
+    if path.parent() != Some(dir)
+    {
+        return Err(anyhow!("недопустимый id файла {}", id));
+    }
+    Ok(path)
+}
+///Имя файла без пути, чтобы отправитель не мог записать файл за пределы директории
+fn safe_name(name: &str, id: &str) -> String
+{
+    Path::new(name).file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| !n.ends_with(".part"))
+        .map(|n| n.to_owned())
+        .unwrap_or_else(|| id.to_owned())
+}
+
+///Занимает в директории __dir__ свободное имя для файла __name__, к занятому имени добавляется номер: `report (1).pdf`,
+/// файл создается пустым чтобы одновременно принятый файл с тем же именем не получил это же имя
+async fn reserve(dir: &Path, name: &str) -> std::io::Result<PathBuf>
+{
+    let name = Path::new(name);
+    let stem = name.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+    let extension = name.extension().and_then(|e| e.to_str()).map(|e| [".", e].concat()).unwrap_or_default();
+    let mut path = dir.join(name);
+    let mut n = 0;
+    loop
+    {
+        match OpenOptions::new().write(true).create_new(true).open(&path).await
+        {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists =>
+            {
+                n += 1;
+                path = dir.join(format!("{} ({}){}", stem, n, extension));
+            },
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+///Размер и sha256 файла
+async fn file_hash(path: &Path) -> std::io::Result<(u64, String)>
+{
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop
+    {
+        let read = file.read(&mut buffer).await?;
+        if read == 0
+        {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    let hash = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    Ok((size, hash))
+}
+
+///Регистрация отправки в списке соединения, при завершении отправки удаляется
+struct Registration
+{
+    transfers: Transfers,
+    id: String
+}
+impl Drop for Registration
+{
+    fn drop(&mut self)
+    {
+        if let Ok(mut guard) = self.transfers.lock()
+        {
+            guard.remove(&self.id);
+        }
+    }
+}
+
+///Отправка файла, если получатель уже принимал часть этого файла то отправка продолжается с подтвержденной позиции
+pub(crate) async fn send_file(outbound: &Outbound, path: &Path, settings: &FileSettings) -> Result<(), TransferError>
+{
+    let (size, hash) = file_hash(path).await?;
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_owned();
+    //id это хэш содержимого, поэтому после переподключения получатель найдет недокачанный файл
+    let id = hash.clone();
+    let (sender, mut responses) = unbounded();
+    let _registration =
+    {
+        let mut guard = outbound.transfers().lock().unwrap();
+        if guard.contains_key(&id)
+        {
+            return Err(TransferError::Rejected(format!("файл {} уже отправляется", &name)));
+        }
+        guard.insert(id.clone(), sender);
+        Registration { transfers: outbound.transfers().clone(), id: id.clone() }
+    };
+    outbound.send_bulk(encode(&FileFrame::Offer { id: id.clone(), name, size, hash }, &[])).map_err(|_| TransferError::Interrupted)?;
+    let mut offset = match responses.next().await
+    {
+        Some(FileFrame::Accept { offset, .. }) => offset,
+        Some(FileFrame::Reject { reason, .. }) => return Err(TransferError::Rejected(reason)),
+        Some(frame) => return Err(TransferError::Rejected(format!("неожиданный ответ {:?}", frame))),
+        None => return Err(TransferError::Interrupted)
+    };
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut acked = offset;
+    let mut buffer = vec![0u8; settings.block_size.max(1)];
+    let window = (settings.block_size.max(1) * settings.window.max(1)) as u64;
+    loop
+    {
+        let read = file.read(&mut buffer).await?;
+        if read == 0
+        {
+            break;
+        }
+        outbound.send_bulk(encode(&FileFrame::Data { id: id.clone(), offset }, &buffer[..read])).map_err(|_| TransferError::Interrupted)?;
+        offset += read as u64;
+        while offset - acked > window
+        {
+            acked = wait_response(&mut responses).await?.unwrap_or(acked);
+        }
+    }
+    outbound.send_bulk(encode(&FileFrame::Complete { id }, &[])).map_err(|_| TransferError::Interrupted)?;
+    loop
+    {
+        match responses.next().await
+        {
+            Some(FileFrame::Verified { ok: true, .. }) => return Ok(()),
+            Some(FileFrame::Verified { ok: false, .. }) => return Err(TransferError::Corrupted),
+            Some(frame) =>
+            {
+                wait_frame(frame)?;
+            },
+            None => return Err(TransferError::Interrupted)
+        }
+    }
+}
+///Ожидание подтверждения, возвращает подтвержденную позицию
+async fn wait_response(responses: &mut UnboundedReceiver<FileFrame>) -> Result<Option<u64>, TransferError>
+{
+    match responses.next().await
+    {
+        Some(frame) => wait_frame(frame),
+        None => Err(TransferError::Interrupted)
+    }
+}
+fn wait_frame(frame: FileFrame) -> Result<Option<u64>, TransferError>
+{
+    match frame
+    {
+        FileFrame::Ack { offset, .. } => Ok(Some(offset)),
+        FileFrame::Reject { reason, .. } => Err(TransferError::Rejected(reason)),
+        _ => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashMap;
+    use super::{decode, encode, part_path, receive_frame, reserve, safe_name, FileFrame, FileSettings, Inbox};
+
+    #[test]
+    fn test_frame_encoding()
+    {
+        let message = encode(&FileFrame::Data { id: "abc".to_owned(), offset: 10 }, &[1, 2, 3]).into_data();
+        let (frame, data) = decode(&message).unwrap();
+        assert!(matches!(frame, FileFrame::Data { id, offset: 10 } if id == "abc"));
+        assert_eq!(data, &[1, 2, 3]);
+        assert!(decode(&message[..3]).is_err());
+    }
+
+    #[test]
+    fn test_safe_name()
+    {
+        assert_eq!(safe_name("../../etc/passwd", "id"), "passwd");
+        assert_eq!(safe_name("report.pdf", "id"), "report.pdf");
+        assert_eq!(safe_name("..", "id"), "id");
+        assert_eq!(safe_name("x.part", "id"), "id");
+    }
+
+    #[tokio::test]
+    async fn test_reserve()
+    {
+        let dir = std::env::temp_dir().join("websocket_service_test").join("reserve");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        //принятый файл с тем же именем не перезаписывает уже принятый
+        assert_eq!(reserve(&dir, "report.pdf").await.unwrap(), dir.join("report.pdf"));
+        assert_eq!(reserve(&dir, "report.pdf").await.unwrap(), dir.join("report (1).pdf"));
+        assert_eq!(reserve(&dir, "report.pdf").await.unwrap(), dir.join("report (2).pdf"));
+        assert_eq!(reserve(&dir, "README").await.unwrap(), dir.join("README"));
+        assert_eq!(reserve(&dir, "README").await.unwrap(), dir.join("README (1)"));
+        //одинаковые файлы от разных отправителей принимаются в разные файлы
+        let id = "ab".repeat(32);
+        let first = part_path(&dir, &id, Some("10.0.0.1:5000".parse().unwrap())).unwrap();
+        assert_eq!(part_path(&dir, &id, Some("10.0.0.1:6000".parse().unwrap())).unwrap(), first);
+        assert_ne!(part_path(&dir, &id, Some("10.0.0.2:5000".parse().unwrap())).unwrap(), first);
+        assert_eq!(part_path(&dir, &id, Some("[::1]:5000".parse().unwrap())).unwrap().parent(), Some(dir.as_path()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_offer_id()
+    {
+        let dir = std::env::temp_dir().join("websocket_service_test").join("offer_id");
+        let settings = FileSettings::new(&dir);
+        let inbox = Inbox::new();
+        let mut incoming = HashMap::new();
+        let hash = "ab".repeat(32);
+        let offer = |id: &str, hash: &str| FileFrame::Offer { id: id.to_owned(), name: "x".to_owned(), size: 1, hash: hash.to_owned() };
+        //выход за пределы директории, абсолютный путь, id не в нижнем регистре и id не совпадающий с хэшем
+        for (id, hash) in [("../../traversal", "../../traversal"), ("/tmp/traversal", "/tmp/traversal"), (&"AB".repeat(32), &"AB".repeat(32)), (&"cd".repeat(32), &hash)]
+        {
+            assert!(receive_frame(&mut incoming, Some(&settings), offer(id, hash), &[], None, &inbox).await.is_err());
+        }
+        assert!(incoming.is_empty());
+        assert!(!dir.join("../../traversal.part").exists());
+        let reply = receive_frame(&mut incoming, Some(&settings), offer(&hash, &hash), &[], None, &inbox).await.unwrap();
+        assert!(matches!(reply, Some(FileFrame::Accept { offset: 0, .. })));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{fmt::Debug, sync::Arc};
+use tokio_tungstenite::tungstenite::{handshake::client::Request, http::{self, header, uri::PathAndQuery, HeaderName, HeaderValue, Uri}};
+
+///Дополнительные заголовки, параметры и cookie запроса рукопожатия
+#[derive(Debug, Clone, Default)]
+pub struct Handshake
+{
+    headers: Vec<(String, String)>,
+    query: Vec<(String, String)>,
+    cookies: Vec<(String, String)>
+}
+impl Handshake
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+    ///Заголовок запроса, например `authorization`
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self
+    {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+    ///Заголовок `authorization: Bearer <token>`, заменяет заданный ранее заголовок `authorization`
+    pub fn bearer(mut self, token: impl AsRef<str>) -> Self
+    {
+        self.headers.retain(|(name, _)| !name.eq_ignore_ascii_case(header::AUTHORIZATION.as_str()));
+        self.header(header::AUTHORIZATION.as_str(), format!("Bearer {}", token.as_ref()))
+    }
+    ///Параметр добавляемый к строке запроса адреса сервера
+    pub fn query(mut self, name: impl Into<String>, value: impl Into<String>) -> Self
+    {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+    pub fn cookie(mut self, name: impl Into<String>, value: impl Into<String>) -> Self
+    {
+        self.cookies.push((name.into(), value.into()));
+        self
+    }
+    ///Токен заданный через `bearer`, его клиент отправляет когда сервер просит обновить токен
+    pub(crate) fn token(&self) -> Option<&str>
+    {
+        self.headers.iter().rev()
+            .find(|(name, _)| name.eq_ignore_ascii_case(header::AUTHORIZATION.as_str()))
+            .and_then(|(_, value)| value.strip_prefix("Bearer "))
+    }
+    ///Добавление параметров в запрос рукопожатия, при некорректном заголовке возвращается ошибка
+    pub(crate) fn apply(&self, request: &mut Request) -> Result<(), http::Error>
+    {
+        for (name, value) in &self.headers
+        {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(http::Error::from)?;
+            let value = HeaderValue::from_str(value).map_err(http::Error::from)?;
+            //заголовок authorization может быть только один
+            if name == header::AUTHORIZATION
+            {
+                request.headers_mut().insert(name, value);
+            }
+            else
+            {
+                request.headers_mut().append(name, value);
+            }
+        }
+        if !self.query.is_empty()
+        {
+            let mut parts = request.uri().clone().into_parts();
+            let mut path = parts.path_and_query.as_ref().map(|p| p.as_str().to_owned()).unwrap_or_else(|| "/".to_owned());
+            for (i, (name, value)) in self.query.iter().enumerate()
+            {
+                let separator = if i == 0 && !path.contains('?') { '?' } else { '&' };
+                path = format!("{}{}{}={}", path, separator, encode(name), encode(value));
+            }
+            parts.path_and_query = Some(PathAndQuery::try_from(path).map_err(http::Error::from)?);
+            *request.uri_mut() = Uri::from_parts(parts).map_err(http::Error::from)?;
+        }
+        if !self.cookies.is_empty()
+        {
+            let mut cookies: Vec<String> = request.headers().get_all(header::COOKIE).iter().filter_map(|c| c.to_str().ok().map(str::to_owned)).collect();
+            cookies.extend(self.cookies.iter().map(|(name, value)| format!("{}={}", name, value)));
+            let value = HeaderValue::from_str(&cookies.join("; ")).map_err(http::Error::from)?;
+            request.headers_mut().insert(header::COOKIE, value);
+        }
+        Ok(())
+    }
+}
+
+///Кодирование значения для строки запроса, все символы кроме незарезервированных заменяются на `%XX`
+fn encode(value: &str) -> String
+{
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes()
+    {
+        match b
+        {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b))
+        }
+    }
+    encoded
+}
+
+///Источник параметров рукопожатия, вызывается перед каждой попыткой подключения,
+/// поэтому после обновления токена при переподключении используется уже новый токен
+#[derive(Clone)]
+pub struct HandshakeBuilder(Arc<dyn Fn() -> Handshake + Send + Sync>);
+impl HandshakeBuilder
+{
+    pub fn new<F>(f: F) -> Self where F: Fn() -> Handshake + Send + Sync + 'static
+    {
+        Self(Arc::new(f))
+    }
+    pub(crate) fn build(&self) -> Handshake
+    {
+        (self.0)()
+    }
+}
+impl From<Handshake> for HandshakeBuilder
+{
+    fn from(handshake: Handshake) -> Self
+    {
+        Self::new(move || handshake.clone())
+    }
+}
+impl Debug for HandshakeBuilder
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        f.write_str("HandshakeBuilder")
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use super::Handshake;
+
+    #[test]
+    fn test_apply()
+    {
+        let mut request = "ws://127.0.0.1:3010/chat?room=1".into_client_request().unwrap();
+        Handshake::new()
+            .bearer("token")
+            .header("x-tenant", "acme")
+            .query("user", "иван петров")
+            .cookie("session", "1")
+            .cookie("theme", "dark")
+            .apply(&mut request)
+            .unwrap();
+        assert_eq!(request.uri().to_string(), "ws://127.0.0.1:3010/chat?room=1&user=%D0%B8%D0%B2%D0%B0%D0%BD%20%D0%BF%D0%B5%D1%82%D1%80%D0%BE%D0%B2");
+        assert_eq!(request.headers()["authorization"], "Bearer token");
+        assert_eq!(request.headers()["x-tenant"], "acme");
+        assert_eq!(request.headers()["cookie"], "session=1; theme=dark");
+        assert_eq!(Handshake::new().bearer("old").bearer("new").token(), Some("new"));
+        let mut request = "ws://127.0.0.1:3010/".into_client_request().unwrap();
+        request.headers_mut().insert("authorization", "Basic dXNlcg==".parse().unwrap());
+        Handshake::new().bearer("old").header("x-tenant", "acme").bearer("new").apply(&mut request).unwrap();
+        assert_eq!(request.headers().get_all("authorization").iter().collect::<Vec<_>>(), ["Bearer new"]);
+        assert_eq!(Handshake::new().header("x-tenant", "acme").token(), None);
+        assert!(Handshake::new().header("bad header", "1").apply(&mut request).is_err());
+    }
+}
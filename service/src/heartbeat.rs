@@ -0,0 +1,136 @@
+use std::{sync::atomic::{AtomicU64, AtomicUsize, Ordering}, time::Duration};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use crate::transport::Outbound;
+
+///Настройки автоматической проверки соединения
+/// __interval__ как часто отправлять ping
+/// __timeout__ сколько ждать ответа после ping, если за это время от второй стороны ничего не пришло то соединение разрывается
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatSettings
+{
+    pub interval: Duration,
+    pub timeout: Duration
+}
+impl Default for HeartbeatSettings
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(10)
+        }
+    }
+}
+
+///Проверка что вторая сторона соединения жива, любой входящий фрейм (в том числе pong) считается ответом,
+/// пока обработка сообщений задерживает чтение из соединения ответ на ping не ожидается
+pub(crate) struct Heartbeat
+{
+    settings: Option<HeartbeatSettings>,
+    started: Instant,
+    ///время последнего входящего фрейма в мс от `started`
+    last_seen: AtomicU64,
+    ///количество обработок сообщений которые сейчас задерживают чтение из соединения
+    busy: AtomicUsize
+}
+///Обработка сообщений во время которой фреймы из соединения не читаются, см. `Heartbeat::busy`
+#[cfg(feature = "client")]
+pub(crate) struct Busy<'a>(&'a Heartbeat);
+#[cfg(feature = "client")]
+impl Drop for Busy<'_>
+{
+    fn drop(&mut self)
+    {
+        //ответ на ping мог прийти пока соединение не читалось
+        self.0.received();
+        self.0.busy.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+impl Heartbeat
+{
+    pub fn new(settings: Option<HeartbeatSettings>) -> Self
+    {
+        Self
+        {
+            settings,
+            started: Instant::now(),
+            last_seen: AtomicU64::new(0),
+            busy: AtomicUsize::new(0)
+        }
+    }
+    fn elapsed(&self) -> u64
+    {
+        self.started.elapsed().as_millis() as u64
+    }
+    ///Получен фрейм от второй стороны
+    pub fn received(&self)
+    {
+        self.last_seen.store(self.elapsed(), Ordering::Relaxed);
+    }
+    ///Начало обработки сообщений которая задерживает чтение из соединения, до завершения обработки
+    /// вторая сторона не может считаться не ответившей
+    #[cfg(feature = "client")]
+    pub fn busy(&self) -> Busy<'_>
+    {
+        self.busy.fetch_add(1, Ordering::Relaxed);
+        Busy(self)
+    }
+    ///Отправка ping, завершается только если вторая сторона не ответила вовремя,
+    /// если проверка отключена то никогда не завершается
+    pub async fn run(&self, sender: &Outbound)
+    {
+        let Some(settings) = self.settings else
+        {
+            return std::future::pending().await;
+        };
+        loop
+        {
+            tokio::time::sleep(settings.interval).await;
+            let ping_at = self.elapsed();
+            let _ = sender.send_message(Message::Ping(ping_at.to_be_bytes().to_vec()));
+            tokio::time::sleep(settings.timeout).await;
+            if self.busy.load(Ordering::Relaxed) == 0 && self.last_seen.load(Ordering::Relaxed) < ping_at
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::Duration;
+    use crate::transport::Outbound;
+    use super::{Heartbeat, HeartbeatSettings};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat()
+    {
+        let settings = HeartbeatSettings { interval: Duration::from_millis(100), timeout: Duration::from_millis(100) };
+        let (sender, _stream) = Outbound::new(None, None, None, |_| ());
+        //вторая сторона не отвечает
+        let heartbeat = Heartbeat::new(Some(settings));
+        assert!(tokio::time::timeout(Duration::from_millis(250), heartbeat.run(&sender)).await.is_ok());
+        //пока обработчик задерживает чтение соединение не разрывается
+        let heartbeat = Heartbeat::new(Some(settings));
+        let busy = heartbeat.busy();
+        let run = heartbeat.run(&sender);
+        tokio::pin!(run);
+        assert!(tokio::time::timeout(Duration::from_secs(1), &mut run).await.is_err());
+        drop(busy);
+        assert!(tokio::time::timeout(Duration::from_millis(150), &mut run).await.is_err());
+        assert!(tokio::time::timeout(Duration::from_millis(250), &mut run).await.is_ok());
+        //ответы второй стороны продлевают соединение
+        let heartbeat = Heartbeat::new(Some(settings));
+        let run = heartbeat.run(&sender);
+        tokio::pin!(run);
+        for _ in 0..20
+        {
+            assert!(tokio::time::timeout(Duration::from_millis(50), &mut run).await.is_err());
+            heartbeat.received();
+        }
+    }
+}
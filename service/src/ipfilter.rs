@@ -0,0 +1,146 @@
+use std::{net::IpAddr, str::FromStr, sync::{atomic::{AtomicU64, Ordering}, Arc, RwLock}};
+use ipnet::IpNet;
+pub use ipnet::AddrParseError;
+
+#[derive(Debug, Default)]
+struct Lists
+{
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>
+}
+
+#[derive(Debug, Default)]
+struct Inner
+{
+    lists: RwLock<Lists>,
+    rejected: AtomicU64
+}
+
+///Списки разрешенных и запрещенных подсетей (IPv4 и IPv6) проверяемые при приеме tcp подключения, до рукопожатия  
+///подключение с адреса из запрещенного списка закрывается, если разрешенный список не пустой то принимаются только адреса из него  
+///копии фильтра общие, поэтому списки можно менять во время работы сервера через копию оставленную при запуске
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter(Arc<Inner>);
+impl IpFilter
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+    ///Добавление подсети `10.0.0.0/8`, `2001:db8::/32` или одного адреса `192.168.1.10` в разрешенный список
+    pub fn allow(&self, net: &str) -> Result<(), AddrParseError>
+    {
+        let net = parse(net)?;
+        self.0.lists.write().unwrap().allow.push(net);
+        Ok(())
+    }
+    ///Добавление подсети или адреса в запрещенный список
+    pub fn deny(&self, net: &str) -> Result<(), AddrParseError>
+    {
+        let net = parse(net)?;
+        self.0.lists.write().unwrap().deny.push(net);
+        Ok(())
+    }
+    ///Удаление подсети из обоих списков, возвращает true если подсеть была в списках
+    pub fn remove(&self, net: &str) -> Result<bool, AddrParseError>
+    {
+        let net = parse(net)?;
+        let mut lists = self.0.lists.write().unwrap();
+        let before = lists.allow.len() + lists.deny.len();
+        lists.allow.retain(|n| n != &net);
+        lists.deny.retain(|n| n != &net);
+        Ok(lists.allow.len() + lists.deny.len() != before)
+    }
+    ///Замена разрешенного списка, при ошибке разбора список не меняется
+    pub fn set_allow<I, S>(&self, nets: I) -> Result<(), AddrParseError> where I: IntoIterator<Item = S>, S: AsRef<str>
+    {
+        let nets = nets.into_iter().map(|n| parse(n.as_ref())).collect::<Result<Vec<_>, _>>()?;
+        self.0.lists.write().unwrap().allow = nets;
+        Ok(())
+    }
+    ///Замена запрещенного списка, при ошибке разбора список не меняется
+    pub fn set_deny<I, S>(&self, nets: I) -> Result<(), AddrParseError> where I: IntoIterator<Item = S>, S: AsRef<str>
+    {
+        let nets = nets.into_iter().map(|n| parse(n.as_ref())).collect::<Result<Vec<_>, _>>()?;
+        self.0.lists.write().unwrap().deny = nets;
+        Ok(())
+    }
+    ///Разрешено ли подключение с адреса __ip__, запрещенный список важнее разрешенного
+    pub fn allows(&self, ip: IpAddr) -> bool
+    {
+        //IPv4 адрес подключения к сокету IPv6 приходит в виде ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        let lists = self.0.lists.read().unwrap();
+        !lists.deny.iter().any(|n| n.contains(&ip)) && (lists.allow.is_empty() || lists.allow.iter().any(|n| n.contains(&ip)))
+    }
+    ///Сколько подключений отклонено фильтром
+    pub fn rejected(&self) -> u64
+    {
+        self.0.rejected.load(Ordering::Relaxed)
+    }
+    ///Проверка адреса подключения, отклоненные подключения учитываются в `rejected`
+    pub(crate) fn check(&self, ip: IpAddr) -> bool
+    {
+        let allowed = self.allows(ip);
+        if !allowed
+        {
+            self.0.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+}
+
+///Сеть без битов адреса узла (`10.1.2.3/8` -> `10.0.0.0/8`), чтобы добавление и удаление сравнивали одинаковые записи
+fn parse(net: &str) -> Result<IpNet, AddrParseError>
+{
+    let net = net.trim();
+    IpNet::from_str(net).or_else(|e| IpAddr::from_str(net).map(IpNet::from).map_err(|_| e)).map(|n| n.trunc())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::net::IpAddr;
+    use super::IpFilter;
+
+    fn ip(ip: &str) -> IpAddr
+    {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_filter()
+    {
+        let filter = IpFilter::new();
+        assert!(filter.allows(ip("8.8.8.8")));
+        filter.deny("10.0.0.0/8").unwrap();
+        filter.deny("2001:db8::/32").unwrap();
+        filter.deny("192.168.1.10").unwrap();
+        assert!(!filter.allows(ip("10.1.2.3")));
+        assert!(!filter.allows(ip("::ffff:10.1.2.3")));
+        assert!(!filter.allows(ip("2001:db8::1")));
+        assert!(!filter.allows(ip("192.168.1.10")));
+        assert!(filter.allows(ip("192.168.1.11")));
+        assert!(filter.allows(ip("2001:db9::1")));
+        //копия фильтра общая с исходным
+        let copy = filter.clone();
+        copy.set_allow(["192.168.0.0/16", "::1"]).unwrap();
+        assert!(!filter.allows(ip("8.8.8.8")));
+        assert!(filter.allows(ip("192.168.1.11")));
+        assert!(filter.allows(ip("::1")));
+        assert!(filter.remove("192.168.1.10").unwrap());
+        assert!(!filter.remove("192.168.1.10").unwrap());
+        assert!(filter.allows(ip("192.168.1.10")));
+        assert!(copy.set_deny(["10.0.0.0/8", "bad"]).is_err());
+        assert!(!filter.allows(ip("10.1.2.3")));
+        assert!(filter.deny("10.0.0.0/33").is_err());
+        //сеть записанная с адресом узла совпадает с той же сетью без него
+        filter.deny("172.16.5.4/12").unwrap();
+        assert!(!filter.allows(ip("172.31.0.1")));
+        assert!(filter.remove("172.16.0.0/12").unwrap());
+        assert!(!filter.remove("172.20.0.0/12").unwrap());
+        assert!(!filter.check(ip("8.8.8.8")));
+        assert!(filter.check(ip("192.168.1.1")));
+        assert_eq!(copy.rejected(), 1);
+    }
+}
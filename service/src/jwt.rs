@@ -0,0 +1,336 @@
+use std::{fmt::{Debug, Display}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc, RwLock}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use jsonwebtoken::{jwk::{AlgorithmParameters, JwkSet, KeyAlgorithm}, Algorithm, DecodingKey, Validation};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::{handshake::server::Request, http::header, protocol::{frame::coding::CloseCode, CloseFrame}, Message};
+use crate::{auth::{self, AuthFrame}, transport::Outbound};
+
+///Ошибка загрузки ключей или проверки токена
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError
+{
+    ///не удалось прочитать файл
+    Io
+    {
+        path: PathBuf,
+        error: String
+    },
+    ///файл не является JWKS или в нем нет ключей HS256/RS256
+    InvalidKeys(String),
+    ///в запросе нет токена
+    MissingToken,
+    ///токен не прошел проверку: подпись, срок действия, издатель или получатель
+    InvalidToken(String),
+    ///новый токен выдан другому субъекту (`sub`)
+    SubjectChanged
+}
+impl Display for AuthError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            AuthError::Io { path, error } => write!(f, "Ошибка чтения файла {} -> {}", path.display(), error),
+            AuthError::InvalidKeys(e) => write!(f, "Ошибка загрузки ключей JWKS: {}", e),
+            AuthError::MissingToken => f.write_str("Токен не передан"),
+            AuthError::InvalidToken(e) => write!(f, "Токен не прошел проверку: {}", e),
+            AuthError::SubjectChanged => f.write_str("Новый токен выдан другому субъекту")
+        }
+    }
+}
+impl std::error::Error for AuthError {}
+
+///Что делать когда истек срок действия (`exp`) токена подключенного клиента
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExpiryAction
+{
+    ///соединение закрывается с кодом 1008
+    #[default]
+    Close,
+    ///клиенту отправляется запрос нового токена, если за __grace__ клиент не прислал действующий токен то соединение закрывается
+    Reauthenticate
+    {
+        grace: Duration
+    }
+}
+
+struct Key
+{
+    id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey
+}
+
+///Проверка JWT (HS256 или RS256) при подключении клиента, ключи загружаются из локального JWKS файла
+///токен передается в заголовке `authorization: Bearer <token>` или в параметре запроса `access_token`,
+///без действующего токена подключение отклоняется при рукопожатии с кодом 401
+///claims токена доступны обработчику через `Server::connection_info`
+#[derive(Clone)]
+pub struct JwtAuth
+{
+    path: PathBuf,
+    keys: Arc<RwLock<Vec<Key>>>,
+    audience: Option<Vec<String>>,
+    issuer: Option<Vec<String>>,
+    on_expiry: ExpiryAction
+}
+impl Debug for JwtAuth
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        f.debug_struct("JwtAuth").field("path", &self.path).field("audience", &self.audience).field("issuer", &self.issuer).field("on_expiry", &self.on_expiry).finish()
+    }
+}
+impl JwtAuth
+{
+    ///Ключи из JWKS файла __path__, ключи других алгоритмов пропускаются
+    pub fn from_jwks(path: impl AsRef<Path>) -> Result<Self, AuthError>
+    {
+        let path = path.as_ref().to_owned();
+        let keys = load(&path)?;
+        Ok(Self
+        {
+            path,
+            keys: Arc::new(RwLock::new(keys)),
+            audience: None,
+            issuer: None,
+            on_expiry: ExpiryAction::default()
+        })
+    }
+    ///Токен должен быть выдан для одного из получателей (`aud`)
+    pub fn with_audience<I, S>(mut self, audience: I) -> Self where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        self.audience = Some(audience.into_iter().map(Into::into).collect());
+        self
+    }
+    ///Токен должен быть выдан одним из издателей (`iss`)
+    pub fn with_issuer<I, S>(mut self, issuer: I) -> Self where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        self.issuer = Some(issuer.into_iter().map(Into::into).collect());
+        self
+    }
+    pub fn on_expiry(mut self, action: ExpiryAction) -> Self
+    {
+        self.on_expiry = action;
+        self
+    }
+    ///Повторная загрузка ключей из того же файла, например после ротации, при ошибке остаются старые ключи
+    pub fn reload(&self) -> Result<(), AuthError>
+    {
+        let keys = load(&self.path)?;
+        *self.keys.write().unwrap() = keys;
+        logger::info!("Ключи JWKS {} перезагружены", self.path.display());
+        Ok(())
+    }
+    ///Проверка токена, возвращает его claims
+    pub fn validate(&self, token: &str) -> Result<serde_json::Value, AuthError>
+    {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+        let keys = self.keys.read().unwrap();
+        //если в заголовке токена нет kid то подходит любой ключ того же алгоритма
+        let key = keys.iter()
+            .filter(|k| k.algorithm == header.alg)
+            .find(|k| header.kid.is_none() || k.id == header.kid)
+            .ok_or_else(|| AuthError::InvalidToken(format!("нет ключа {:?} для алгоритма {:?}", header.kid, header.alg)))?;
+        let mut validation = Validation::new(key.algorithm);
+        validation.leeway = 0;
+        validation.validate_aud = self.audience.is_some();
+        if let Some(audience) = self.audience.as_ref()
+        {
+            validation.set_audience(audience);
+        }
+        if let Some(issuer) = self.issuer.as_ref()
+        {
+            validation.set_issuer(issuer);
+        }
+        jsonwebtoken::decode::<serde_json::Value>(token, &key.key, &validation)
+            .map(|t| t.claims)
+            .map_err(|e| AuthError::InvalidToken(e.to_string()))
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Key>, AuthError>
+{
+    let data = std::fs::read(path).map_err(|e| AuthError::Io { path: path.to_owned(), error: e.to_string() })?;
+    let set: JwkSet = serde_json::from_slice(&data).map_err(|e| AuthError::InvalidKeys(e.to_string()))?;
+    let mut keys = Vec::new();
+    for jwk in &set.keys
+    {
+        let algorithm = match (jwk.common.key_algorithm, &jwk.algorithm)
+        {
+            (Some(KeyAlgorithm::HS256) | None, AlgorithmParameters::OctetKey(_)) => Algorithm::HS256,
+            (Some(KeyAlgorithm::RS256) | None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+            (algorithm, _) =>
+            {
+                logger::warn!("Ключ {:?} с алгоритмом {:?} не поддерживается и пропущен", &jwk.common.key_id, algorithm);
+                continue;
+            }
+        };
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| AuthError::InvalidKeys(e.to_string()))?;
+        keys.push(Key { id: jwk.common.key_id.clone(), algorithm, key });
+    }
+    if keys.is_empty()
+    {
+        return Err(AuthError::InvalidKeys(format!("в файле {} нет ключей HS256 или RS256", path.display())));
+    }
+    Ok(keys)
+}
+
+///Токен из заголовка `authorization: Bearer <token>` или параметра запроса `access_token`
+pub(crate) fn token(request: &Request) -> Option<String>
+{
+    let bearer = request.headers().get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_owned());
+    bearer.or_else(|| request.uri().query()?.split('&').find_map(|p| p.strip_prefix("access_token=")).and_then(decode))
+}
+///Значение параметра запроса закодированное клиентом как `%XX` (см. `Handshake::query`)
+fn decode(value: &str) -> Option<String>
+{
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first()
+    {
+        if b == b'%'
+        {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        }
+        else
+        {
+            decoded.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+///Срок действия токена подключения, unix время в секундах
+fn expires(claims: &serde_json::Value) -> u64
+{
+    claims.get("exp").and_then(serde_json::Value::as_u64).unwrap_or(u64::MAX)
+}
+fn subject(claims: &serde_json::Value) -> Option<String>
+{
+    claims.get("sub").and_then(serde_json::Value::as_str).map(str::to_owned)
+}
+
+///Сессия подключенного клиента: срок действия токена и его обновление без переподключения
+pub(crate) struct Session
+{
+    auth: Option<JwtAuth>,
+    subject: Option<String>,
+    expires: AtomicU64,
+    refreshed: Notify
+}
+impl Session
+{
+    ///__claims__ токена с которым клиент подключился, без __auth__ срок действия не проверяется
+    pub fn new(auth: Option<JwtAuth>, claims: Option<&serde_json::Value>) -> Self
+    {
+        Self
+        {
+            auth,
+            subject: claims.and_then(subject),
+            expires: AtomicU64::new(claims.map(expires).unwrap_or(u64::MAX)),
+            refreshed: Notify::new()
+        }
+    }
+    ///Новый токен полученный от клиента, возвращает его claims
+    pub fn refresh(&self, token: &str) -> Result<serde_json::Value, AuthError>
+    {
+        let auth = self.auth.as_ref().ok_or_else(|| AuthError::InvalidToken("сервер не проверяет токены".to_owned()))?;
+        let claims = auth.validate(token)?;
+        if subject(&claims) != self.subject
+        {
+            return Err(AuthError::SubjectChanged);
+        }
+        self.expires.store(expires(&claims), Ordering::Relaxed);
+        self.refreshed.notify_waiters();
+        Ok(claims)
+    }
+    ///Ожидание окончания срока действия токена, завершается когда соединение нужно закрыть,
+    /// фрейм закрытия к этому моменту уже поставлен в очередь отправки
+    ///если проверка токенов отключена то никогда не завершается
+    pub async fn run(&self, sender: &Outbound)
+    {
+        let Some(auth) = self.auth.as_ref() else
+        {
+            return std::future::pending().await;
+        };
+        loop
+        {
+            let refreshed = self.refreshed.notified();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let left = Duration::from_secs(self.expires.load(Ordering::Relaxed)).saturating_sub(now);
+            tokio::select!
+            {
+                _ = tokio::time::sleep(left) => (),
+                _ = refreshed => continue
+            }
+            match auth.on_expiry
+            {
+                ExpiryAction::Close => break,
+                ExpiryAction::Reauthenticate { grace } =>
+                {
+                    let refreshed = self.refreshed.notified();
+                    if sender.send_message(auth::encode(&AuthFrame::Request)).is_err()
+                    {
+                        break;
+                    }
+                    tokio::select!
+                    {
+                        _ = tokio::time::sleep(grace) => break,
+                        _ = refreshed => continue
+                    }
+                }
+            }
+        }
+        let frame = CloseFrame { code: CloseCode::Policy, reason: "Срок действия токена истек".into() };
+        let _ = sender.send_message(Message::Close(Some(frame)));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use super::{token, AuthError, JwtAuth};
+
+    #[test]
+    fn test_validate()
+    {
+        let dir = std::env::temp_dir().join("websocket_service_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("validate.jwks");
+        //секрет "secret" в base64url
+        std::fs::write(&path, r#"{"keys":[{"kty":"oct","kid":"main","alg":"HS256","k":"c2VjcmV0"},{"kty":"EC","crv":"P-256","x":"f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU","y":"x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0"}]}"#).unwrap();
+        let auth = JwtAuth::from_jwks(&path).unwrap().with_issuer(["auth.local"]);
+        let exp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 60;
+        let sign = |claims: serde_json::Value, kid: &str, secret: &[u8]|
+        {
+            let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256);
+            header.kid = Some(kid.to_owned());
+            jsonwebtoken::encode(&header, &claims, &jsonwebtoken::EncodingKey::from_secret(secret)).unwrap()
+        };
+        let valid = sign(serde_json::json!({ "sub": "user", "iss": "auth.local", "exp": exp }), "main", b"secret");
+        assert_eq!(auth.validate(&valid).unwrap()["sub"], "user");
+        assert!(matches!(auth.validate(&sign(serde_json::json!({ "sub": "user", "iss": "auth.local", "exp": exp }), "main", b"other")), Err(AuthError::InvalidToken(_))));
+        assert!(auth.validate(&sign(serde_json::json!({ "sub": "user", "iss": "auth.local", "exp": exp - 120 }), "main", b"secret")).is_err());
+        assert!(auth.validate(&sign(serde_json::json!({ "sub": "user", "iss": "other", "exp": exp }), "main", b"secret")).is_err());
+        assert!(auth.validate(&sign(serde_json::json!({ "sub": "user", "iss": "auth.local", "exp": exp }), "unknown", b"secret")).is_err());
+        assert!(auth.validate("garbage").is_err());
+        std::fs::write(&path, r#"{"keys":[]}"#).unwrap();
+        assert!(matches!(auth.reload(), Err(AuthError::InvalidKeys(_))));
+        //при ошибке перезагрузки остаются старые ключи
+        assert!(auth.validate(&valid).is_ok());
+        let request = "ws://127.0.0.1:3010/?access_token=abc".into_client_request().unwrap();
+        assert_eq!(token(&request).as_deref(), Some("abc"));
+        let request = "ws://127.0.0.1:3010/?room=1&access_token=a%2Eb%2Fc%3D%3D".into_client_request().unwrap();
+        assert_eq!(token(&request).as_deref(), Some("a.b/c=="));
+        let mut request = "ws://127.0.0.1:3010/?access_token=abc".into_client_request().unwrap();
+        request.headers_mut().insert("authorization", "Bearer xyz".parse().unwrap());
+        assert_eq!(token(&request).as_deref(), Some("xyz"));
+    }
+}
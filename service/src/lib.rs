@@ -1,13 +1,82 @@
 #[cfg(feature = "server")]
 mod server;
+#[cfg(feature = "server")]
+mod router;
+#[cfg(feature = "server")]
+mod security;
 #[cfg(feature = "client")]
 mod client;
+#[cfg(feature = "client")]
+mod state;
 mod retry;
+mod batch;
+mod config;
+mod chunk;
+mod files;
+mod transport;
+mod reconnect;
+#[cfg(feature = "client")]
+mod outbox;
+mod heartbeat;
+#[cfg(feature = "client")]
+mod failover;
+#[cfg(feature = "client")]
+mod handshake;
+#[cfg(feature = "server")]
+mod origin;
+mod limits;
+#[cfg(feature = "server")]
+mod ipfilter;
+mod auth;
+#[cfg(all(feature = "jwt", feature = "server"))]
+mod jwt;
+#[cfg(feature = "seal")]
+mod seal;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unix;
 pub use retry::retry;
+pub use batch::BatchSettings;
+pub use config::{ClientConfig, Dispatch, ServerConfig};
+pub use reconnect::ReconnectPolicy;
+#[cfg(feature = "client")]
+pub use outbox::OutboxSettings;
+pub use heartbeat::HeartbeatSettings;
+#[cfg(feature = "client")]
+pub use failover::{FailoverSettings, FailoverStrategy};
+#[cfg(feature = "client")]
+pub use handshake::{Handshake, HandshakeBuilder};
+#[cfg(feature = "server")]
+pub use origin::OriginPolicy;
+#[cfg(feature = "server")]
+pub use limits::{LimitAction, RateLimits};
+#[cfg(feature = "server")]
+pub use ipfilter::{AddrParseError, IpFilter};
+#[cfg(all(feature = "jwt", feature = "server"))]
+pub use jwt::{AuthError, ExpiryAction, JwtAuth};
+#[cfg(feature = "seal")]
+pub use seal::{Encryption, SealError, Sealing};
+#[cfg(feature = "tls")]
+pub use tls::TlsError;
+#[cfg(all(feature = "tls", feature = "server"))]
+pub use tls::ServerTls;
+#[cfg(all(feature = "tls", feature = "client"))]
+pub use tls::ClientTls;
+pub use tokio_tungstenite::tungstenite::{http::HeaderMap, protocol::WebSocketConfig};
+pub use chunk::{ChunkSettings, ChunkProgress, ChunkDirection};
+pub use files::{FileSettings, ReceivedFile, TransferError};
+pub use transport::SendError;
+#[cfg(feature = "server")]
+pub use server::{ConnectionId, ConnectionInfo, Server};
+#[cfg(feature = "server")]
+pub use router::{Endpoint, Router};
 #[cfg(feature = "server")]
-pub use server::Server;
+pub use security::{security_events, SecurityEvent};
 #[cfg(feature = "client")]
-pub use client::Client;
+pub use client::{Client, ClientHandle};
+#[cfg(feature = "client")]
+pub use state::{ClientState, ConnectError, DisconnectReason};
 
 
 #[cfg(test)]
@@ -66,6 +135,7 @@ mod test
             "Client2"
         }
     }
+    #[allow(dead_code)]
     pub struct Client3;
     impl Client<TransportMessage> for Client3
     {
@@ -74,6 +144,7 @@ mod test
             "Client3"
         }
     }
+    #[allow(dead_code)]
     pub struct Client4;
     impl Client<TransportMessage> for Client4
     {
@@ -84,14 +155,168 @@ mod test
     }
     pub struct WsServer;
     impl Server<TransportMessage> for WsServer{}
+    ///Сервер без своих настроек, у каждого теста свой адрес поэтому тесты не мешают друг другу
+    pub struct TestServer;
+    impl Server<TransportMessage> for TestServer{}
+    pub struct BatchClient;
+    impl Client<TransportMessage> for BatchClient
+    {
+        fn get_id() -> &'static str 
+        {
+            "BatchClient"
+        }
+        fn batching() -> Option<BatchSettings> 
+        {
+            Some(BatchSettings::default())
+        }
+    }
+    pub struct BatchServer;
+    impl Server<TransportMessage> for BatchServer
+    {
+        fn batching() -> Option<BatchSettings> 
+        {
+            Some(BatchSettings::default())
+        }
+    }
+    pub struct ChunkClient;
+    impl Client<TransportMessage> for ChunkClient
+    {
+        fn get_id() -> &'static str 
+        {
+            "ChunkClient"
+        }
+        fn chunking() -> Option<ChunkSettings> 
+        {
+            Some(ChunkSettings::default())
+        }
+        fn on_chunk_progress(progress: ChunkProgress) 
+        {
+            if progress.is_complete()
+            {
+                let _ = CHUNK_PROGRESS.send(progress);
+            }
+        }
+    }
+    pub struct ChunkServer;
+    impl Server<TransportMessage> for ChunkServer
+    {
+        fn chunking() -> Option<ChunkSettings> 
+        {
+            Some(ChunkSettings::default())
+        }
+        fn on_chunk_progress(_addr: std::net::SocketAddr, progress: ChunkProgress) 
+        {
+            if progress.is_complete()
+            {
+                let _ = CHUNK_PROGRESS.send(progress);
+            }
+        }
+    }
+    pub struct FileClient;
+    impl Client<TransportMessage> for FileClient
+    {
+        fn get_id() -> &'static str 
+        {
+            "FileClient"
+        }
+    }
+    pub struct FileServer;
+    impl Server<TransportMessage> for FileServer
+    {
+        fn files() -> Option<FileSettings> 
+        {
+            Some(FileSettings::new(std::env::temp_dir().join("websocket_service_test").join("received")))
+        }
+    }
+    pub struct HandleClient;
+    impl Client<TransportMessage> for HandleClient
+    {
+        fn get_id() -> &'static str 
+        {
+            "HandleClient"
+        }
+    }
+    pub struct StreamServer;
+    impl Server<TransportMessage> for StreamServer{}
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    pub struct TelemetryMessage
+    {
+        cpu: u32
+    }
+    pub struct ChatServer;
+    impl Server<TransportMessage> for ChatServer{}
+    impl Server<TelemetryMessage> for ChatServer{}
+    pub struct TelemetryServer;
+    impl Server<TelemetryMessage> for TelemetryServer{}
+    pub struct RoomServer;
+    impl Server<TransportMessage> for RoomServer{}
     static COUNT: AtomicU32 = AtomicU32::new(0);
+    static BATCH_COUNT: AtomicU32 = AtomicU32::new(0);
+    static CHUNK_COUNT: AtomicU32 = AtomicU32::new(0);
+    ///завершенные передачи больших сообщений ChunkClient и ChunkServer
+    static CHUNK_PROGRESS: once_cell::sync::Lazy<tokio::sync::broadcast::Sender<ChunkProgress>> = once_cell::sync::Lazy::new(|| tokio::sync::broadcast::channel(16).0);
+    static HANDLE_COUNT: AtomicU32 = AtomicU32::new(0);
+    static CONFIRM_COUNT: AtomicU32 = AtomicU32::new(0);
+    static DISPATCH_COUNT: AtomicU32 = AtomicU32::new(0);
+    static DISPATCH_ACTIVE: AtomicU32 = AtomicU32::new(0);
+    static DISPATCH_MAX_ACTIVE: AtomicU32 = AtomicU32::new(0);
+    static UNIX_COUNT: AtomicU32 = AtomicU32::new(0);
+    static TELEMETRY_CPU: AtomicU32 = AtomicU32::new(0);
+    static LIMIT_COUNT: AtomicU32 = AtomicU32::new(0);
+    #[cfg(feature = "seal")]
+    static SEAL_COUNT: AtomicU32 = AtomicU32::new(0);
+    #[cfg(feature = "tls")]
+    static TLS_COUNT: AtomicU32 = AtomicU32::new(0);
+    #[cfg(feature = "tls")]
+    static MTLS_SUBJECT: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
     use std::sync::atomic::AtomicU32;
     use logger::debug;
+    use crate::{BatchSettings, ChunkProgress, ChunkSettings, ClientConfig, Dispatch, FileSettings, HeartbeatSettings, ReconnectPolicy, SendError, ServerConfig, WebSocketConfig};
     #[cfg(feature = "client")]
-    use crate::Client;
+    use crate::{Client, ClientState, ConnectError, DisconnectReason, FailoverSettings, Handshake, HandshakeBuilder};
     #[cfg(feature = "server")]
     use crate::Server;
 
+    ///Свободные локальные адреса, порты занимаются одновременно поэтому не совпадают
+    fn local_addrs<const N: usize>() -> [String; N]
+    {
+        let listeners: Vec<std::net::TcpListener> = (0..N).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        std::array::from_fn(|i| listeners[i].local_addr().unwrap().to_string())
+    }
+    ///Ожидание пока сервер на адресе __addr__ начнет принимать подключения,
+    /// пробное подключение ждет пока сервер его закроет, чтобы не попасть в счетчики теста
+    #[cfg(feature = "server")]
+    async fn listening(addr: &str)
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async
+        {
+            let mut probe = loop
+            {
+                match tokio::net::TcpStream::connect(addr).await
+                {
+                    Ok(probe) => break probe,
+                    Err(_) => tokio::time::sleep(tokio::time::Duration::from_millis(20)).await
+                }
+            };
+            let _ = probe.shutdown().await;
+            let _ = probe.read_to_end(&mut Vec::new()).await;
+        }).await.unwrap();
+    }
+    ///Запуск сервера __S__ с настройками __config__ на свободном локальном адресе,
+    /// возвращает адрес когда сервер уже принимает подключения
+    #[cfg(feature = "server")]
+    async fn serve<S, F, Fut>(config: ServerConfig, f: F) -> String
+    where S: Server<TransportMessage>,
+        F: Send + Sync + 'static + Clone + Fn(std::net::SocketAddr, TransportMessage) -> Fut,
+        Fut: std::future::Future<Output = ()> + Send + Sync + 'static
+    {
+        let [addr] = local_addrs();
+        S::start_server_with_config(&addr, config, f).await;
+        listening(&addr).await;
+        addr
+    }
+
 
     #[cfg(feature = "server")]
     #[cfg(feature = "client")]
@@ -99,6 +324,7 @@ mod test
     ///json -> 1000 итераций теста завершено за: 91.356768ms, 129.009179ms, 126.985547ms, 125.335948ms, 
     ///bin ->  1000 итераций теста завершено за: 172.269761ms, 173.672374ms, 170.545242ms, 
     /// flexbuffers -> 1000 итераций теста завершено за: 148.515492ms, 151.712027ms, 145.205206ms, 
+    #[allow(clippy::unused_unit)]
     pub async fn test_connection()
     {
         logger::StructLogger::initialize_logger();
@@ -132,6 +358,1196 @@ mod test
         logger::info!("{} итераций теста завершено за: {:?}, ", COUNT.load(std::sync::atomic::Ordering::SeqCst), duration);
     }
 
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_batching()
+    {
+        logger::StructLogger::initialize_logger();
+        let addr = serve::<BatchServer, _, _>(ServerConfig::default(), |_addr, _msg|
+        {
+            async move
+            {
+                BATCH_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }).await;
+        BatchClient::start_client(&format!("ws://{}/", addr), |_msg: TransportMessage| {}).await;
+        let start = tokio::time::Instant::now();
+        for _m in 0..1000
+        {
+            BatchClient::send_message(TransportMessage::Test1(TestPayload::default())).await;
+        }
+        let duration = start.elapsed();
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        logger::info!("{} сообщений пакетами отправлено за: {:?}, ", BATCH_COUNT.load(std::sync::atomic::Ordering::SeqCst), duration);
+        assert_eq!(BATCH_COUNT.load(std::sync::atomic::Ordering::SeqCst), 1000);
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_chunking()
+    {
+        logger::StructLogger::initialize_logger();
+        let addr = serve::<ChunkServer, _, _>(ServerConfig::default(), |addr, msg|
+        {
+            async move
+            {
+                //отправляем большое сообщение обратно клиенту
+                ChunkServer::send(msg, &addr).await;
+            }
+        }).await;
+        ChunkClient::start_client(&format!("ws://{}/", addr), |msg: TransportMessage|
+        {
+            //широковещательные сообщения других тестов тоже приходят сюда
+            if matches!(msg, TransportMessage::Test2(s) if s.len() == 5 * 1024 * 1024)
+            {
+                CHUNK_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }).await;
+        let mut progress = CHUNK_PROGRESS.subscribe();
+        ChunkClient::send_message(TransportMessage::Test2("0".repeat(5 * 1024 * 1024))).await;
+        tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+        assert_eq!(CHUNK_COUNT.load(std::sync::atomic::Ordering::SeqCst), 1);
+        //отправка и получение на клиенте и на сервере
+        let mut complete = Vec::new();
+        while let Ok(p) = progress.try_recv()
+        {
+            if p.total >= 5 * 1024 * 1024
+            {
+                complete.push(p.direction);
+            }
+        }
+        assert_eq!(complete.iter().filter(|d| matches!(d, crate::ChunkDirection::Send)).count(), 2);
+        assert_eq!(complete.iter().filter(|d| matches!(d, crate::ChunkDirection::Receive)).count(), 2);
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_file_transfer()
+    {
+        logger::StructLogger::initialize_logger();
+        let dir = std::env::temp_dir().join("websocket_service_test");
+        let received_dir = FileServer::files().unwrap().dir;
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&received_dir).unwrap();
+        let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 253) as u8).collect();
+        let path = dir.join("export.bin");
+        std::fs::write(&path, &content).unwrap();
+        let addr = serve::<FileServer, _, _>(ServerConfig::default(), |_addr, _msg| async move {}).await;
+        FileClient::start_client(&format!("ws://{}/", addr), |_msg: TransportMessage| {}).await;
+        FileClient::send_file(&path).await.unwrap();
+        let received = FileServer::receive_file().await.unwrap();
+        assert_eq!(received.size, content.len() as u64);
+        assert_eq!(std::fs::read(&received.path).unwrap(), content);
+        //недокачанный в прошлый раз файл, отправка должна продолжиться с его конца
+        let resumed: Vec<u8> = content.iter().rev().cloned().collect();
+        std::fs::write(&path, &resumed).unwrap();
+        let hash = sha2::Digest::finalize(<sha2::Sha256 as sha2::Digest>::new_with_prefix(&resumed)).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        std::fs::write(received_dir.join(format!("{}.127.0.0.1.part", hash)), &resumed[..400_000]).unwrap();
+        FileClient::send_file(&path).await.unwrap();
+        let received = FileServer::receive_file().await.unwrap();
+        assert_eq!(std::fs::read(&received.path).unwrap(), resumed);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    pub async fn test_max_message_size()
+    {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message};
+        logger::StructLogger::initialize_logger();
+        let websocket = WebSocketConfig { max_message_size: Some(1024), max_frame_size: Some(1024), ..Default::default() };
+        let addr = serve::<TestServer, _, _>(ServerConfig { websocket, ..Default::default() }, |_addr, _msg| async move {}).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr)).await.unwrap();
+        ws.send(Message::Binary(vec![b'0'; 4096])).await.unwrap();
+        let closed = tokio::time::timeout(tokio::time::Duration::from_secs(3), ws.next()).await.unwrap();
+        match closed
+        {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Size),
+            other => panic!("Ожидалось закрытие соединения с кодом 1009, получено {:?}", other)
+        }
+    }
+
+    ///Нагрузочный тест подключения и отключения 50к клиентов, перед запуском надо поднять лимит открытых файлов:  
+    ///`ulimit -n 200000 && cargo test --release test_connection_churn -- --ignored --nocapture`  
+    ///количество подключений задается переменной окружения WS_CHURN_CONNECTIONS
+    #[cfg(feature = "server")]
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    pub async fn test_connection_churn()
+    {
+        use futures::{SinkExt, StreamExt};
+        logger::StructLogger::initialize_logger();
+        let count: usize = std::env::var("WS_CHURN_CONNECTIONS").ok().and_then(|c| c.parse().ok()).unwrap_or(50_000);
+        let addr: std::net::SocketAddr = serve::<TestServer, _, _>(ServerConfig::default(), |_addr, _msg| async move {}).await.parse().unwrap();
+        let before = crate::server::connections_count();
+        let wait_count = |expected: usize| async move
+        {
+            while crate::server::connections_count() != expected
+            {
+                tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+            }
+        };
+        let start = tokio::time::Instant::now();
+        let connections: Vec<_> = futures::stream::iter(0..count).map(|i| async move
+        {
+            //локальных портов одного адреса на 50к подключений не хватит, поэтому подключаемся с разных адресов 127.0.0.x
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.bind(std::net::SocketAddr::from(([127, 0, 0, 2 + (i % 200) as u8], 0))).unwrap();
+            let stream = socket.connect(addr).await.unwrap();
+            tokio_tungstenite::client_async(format!("ws://{}/", addr), stream).await.unwrap().0
+        }).buffer_unordered(512).collect().await;
+        wait_count(before + count).await;
+        let connected = start.elapsed();
+        let start = tokio::time::Instant::now();
+        futures::stream::iter(connections).for_each_concurrent(512, |mut ws| async move
+        {
+            let _ = ws.close(None).await;
+            let _ = ws.flush().await;
+        }).await;
+        wait_count(before).await;
+        let disconnected = start.elapsed();
+        logger::info!("{} подключений за {:?} ({:.0}/с), отключений за {:?} ({:.0}/с)", count,
+            connected, count as f64 / connected.as_secs_f64(),
+            disconnected, count as f64 / disconnected.as_secs_f64());
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_reconnect_attempts()
+    {
+        use futures::StreamExt;
+        logger::StructLogger::initialize_logger();
+        let config = ClientConfig
+        {
+            reconnect: ReconnectPolicy
+            {
+                initial_delay: std::time::Duration::from_secs(2),
+                jitter: 0.0,
+                max_attempts: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        //сервер на этом порту не запущен, после двух повторных попыток клиент останавливается
+        let [addr] = local_addrs();
+        let client = HandleClient::start_client_with_config(&format!("ws://{}/", addr), config, |_msg: TransportMessage| {}).await;
+        let states: Vec<ClientState> = tokio::time::timeout(tokio::time::Duration::from_secs(10), 
+            client.state_changes().take_while(|s| futures::future::ready(*s != ClientState::Closed)).collect()).await.unwrap();
+        assert!(states.iter().any(|s| matches!(s, ClientState::Disconnected(DisconnectReason::ConnectFailed(_)))));
+        assert!(states.iter().any(|s| matches!(s, ClientState::Reconnecting { attempt: 2, .. })));
+        assert!(!client.is_connected());
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_reconnect_backoff()
+    {
+        use futures::StreamExt;
+        logger::StructLogger::initialize_logger();
+        //сервер принимает подключение и сразу его закрывает
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move
+        {
+            while let Ok((stream, _)) = listener.accept().await
+            {
+                if let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await
+                {
+                    let _ = ws.close(None).await;
+                }
+            }
+        });
+        let config = ClientConfig
+        {
+            reconnect: ReconnectPolicy { initial_delay: std::time::Duration::from_millis(200), jitter: 0.0, ..Default::default() },
+            ..Default::default()
+        };
+        let client = HandleClient::start_client_with_config(&format!("ws://{}/", addr), config, |_msg: TransportMessage| {}).await;
+        //подключения не держатся, поэтому счетчик попыток не сбрасывается и задержка растет
+        let attempts: Vec<u32> = tokio::time::timeout(tokio::time::Duration::from_secs(5), client.state_changes().filter_map(|s| async move
+        {
+            match s
+            {
+                ClientState::Reconnecting { attempt, .. } => Some(attempt),
+                _ => None
+            }
+        }).take(3).collect()).await.unwrap();
+        assert_eq!(attempts, [1, 2, 3]);
+        client.close().await;
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_outbox_reconnect()
+    {
+        use futures::StreamExt;
+        use crate::OutboxSettings;
+        logger::StructLogger::initialize_logger();
+        ///Принятие одного подключения и чтение __count__ сообщений
+        async fn receive(listener: &tokio::net::TcpListener, count: usize) -> Vec<String>
+        {
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            ws.filter_map(|m| async move { serde_json::from_slice(&m.ok()?.into_data()).ok() })
+                .filter_map(|m: TransportMessage| async move { if let TransportMessage::Test2(s) = m { Some(s) } else { None } })
+                .take(count)
+                .collect()
+                .await
+        }
+        //порт занимается только после того как сообщения поставлены в очередь
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = ClientConfig
+        {
+            reconnect: ReconnectPolicy { initial_delay: std::time::Duration::from_millis(100), jitter: 0.0, ..Default::default() },
+            outbox: Some(OutboxSettings::default()),
+            ..Default::default()
+        };
+        let client = HandleClient::start_client_with_config(&format!("ws://{}/", addr), config, |_msg: TransportMessage| {}).await;
+        for i in 0..3
+        {
+            client.send(TransportMessage::Test2(format!("queued-{}", i))).await;
+        }
+        assert_eq!(client.queued().await, 3);
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let mut state = client.state();
+        let sender = client.clone();
+        tokio::spawn(async move
+        {
+            let _ = state.wait_for(|s| matches!(s, ClientState::Connected { .. })).await;
+            sender.send(TransportMessage::Test2("connected".to_owned())).await;
+        });
+        let received = tokio::time::timeout(tokio::time::Duration::from_secs(5), receive(&listener, 4)).await.unwrap();
+        assert_eq!(received, ["queued-0", "queued-1", "queued-2", "connected"]);
+        assert_eq!(client.queued().await, 0);
+        //соединение разорвано сервером, сообщения снова копятся в очереди до переподключения
+        let mut state = client.state();
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), state.wait_for(|s| !matches!(s, ClientState::Connected { .. }))).await.unwrap().unwrap();
+        client.send(TransportMessage::Test2("reconnected".to_owned())).await;
+        let received = tokio::time::timeout(tokio::time::Duration::from_secs(5), receive(&listener, 1)).await.unwrap();
+        assert_eq!(received, ["reconnected"]);
+        client.close().await;
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_send_confirmed()
+    {
+        logger::StructLogger::initialize_logger();
+        let server = serve::<TestServer, _, _>(ServerConfig::default(), |addr, msg|
+        {
+            async move
+            {
+                //ответ клиенту с ожиданием записи в сокет
+                if TestServer::send_confirmed(msg, &addr).await.is_ok()
+                {
+                    CONFIRM_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            }
+        }).await;
+        let unknown: std::net::SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert_eq!(TestServer::try_send(TransportMessage::Test2("none".to_owned()), &unknown).await, Err(SendError::NotConnected));
+        let client = HandleClient::start_client(&format!("ws://{}/", server), |_msg: TransportMessage| {}).await;
+        assert_eq!(client.send_confirmed(TransportMessage::Test2("confirmed".to_owned())).await, Ok(()));
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        assert_eq!(CONFIRM_COUNT.load(std::sync::atomic::Ordering::SeqCst), 1);
+        client.close().await;
+        assert_eq!(client.send_confirmed(TransportMessage::Test2("closed".to_owned())).await, Err(SendError::NotConnected));
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_spawn_dispatch()
+    {
+        use std::sync::atomic::Ordering;
+        logger::StructLogger::initialize_logger();
+        let server = serve::<TestServer, _, _>(ServerConfig::default(), |addr, _msg|
+        {
+            async move
+            {
+                for _ in 0..8
+                {
+                    TestServer::send(TransportMessage::Test2("dispatch".to_owned()), &addr).await;
+                }
+            }
+        }).await;
+        let config = ClientConfig { dispatch: Dispatch::Spawn { max_concurrent: 4 }, ..Default::default() };
+        let client = HandleClient::start_client_with_config_async(&format!("ws://{}/", server), config, |msg: TransportMessage|
+        {
+            async move
+            {
+                //широковещательные сообщения других тестов тоже приходят сюда
+                if !matches!(msg, TransportMessage::Test2(s) if s == "dispatch")
+                {
+                    return;
+                }
+                let active = DISPATCH_ACTIVE.fetch_add(1, Ordering::SeqCst) + 1;
+                DISPATCH_MAX_ACTIVE.fetch_max(active, Ordering::SeqCst);
+                tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+                DISPATCH_ACTIVE.fetch_sub(1, Ordering::SeqCst);
+                DISPATCH_COUNT.fetch_add(1, Ordering::SeqCst);
+            }
+        }).await;
+        client.send(TransportMessage::Test2("start".to_owned())).await;
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        assert_eq!(DISPATCH_COUNT.load(Ordering::SeqCst), 8);
+        assert_eq!(DISPATCH_MAX_ACTIVE.load(Ordering::SeqCst), 4);
+        client.close().await;
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_streams()
+    {
+        use futures::{SinkExt, StreamExt};
+        logger::StructLogger::initialize_logger();
+        let [addr] = local_addrs();
+        let mut incoming = StreamServer::start_server_stream(&addr, ServerConfig::default()).await;
+        //эхо сервер
+        tokio::spawn(async move
+        {
+            let mut sink = StreamServer::sink();
+            while let Some((addr, msg)) = incoming.next().await
+            {
+                sink.send((addr, msg)).await.unwrap();
+            }
+        });
+        listening(&addr).await;
+        let (client, messages) = HandleClient::start_client_stream(&format!("ws://{}/", addr), ClientConfig::default()).await;
+        let mut sink = client.sink();
+        for i in 0..3
+        {
+            sink.send(TransportMessage::Test2(format!("stream-{}", i))).await.unwrap();
+        }
+        //широковещательные сообщения других тестов тоже приходят сюда
+        let echo: Vec<String> = tokio::time::timeout(tokio::time::Duration::from_secs(5), messages.filter_map(|m| async move
+        {
+            match m
+            {
+                TransportMessage::Test2(s) if s.starts_with("stream-") => Some(s),
+                _ => None
+            }
+        }).take(3).collect()).await.unwrap();
+        assert_eq!(echo, vec!["stream-0", "stream-1", "stream-2"]);
+        client.close().await;
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_heartbeat_timeout()
+    {
+        use futures::StreamExt;
+        logger::StructLogger::initialize_logger();
+        //сервер принимает соединение но не читает из него, поэтому не отвечает на ping
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move
+        {
+            let mut peers = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await
+            {
+                if let Ok(ws) = tokio_tungstenite::accept_async(stream).await
+                {
+                    peers.push(ws);
+                }
+            }
+        });
+        let config = ClientConfig
+        {
+            heartbeat: Some(HeartbeatSettings { interval: tokio::time::Duration::from_millis(200), timeout: tokio::time::Duration::from_millis(200) }),
+            ..Default::default()
+        };
+        let client = HandleClient::start_client_with_config(&format!("ws://{}/", addr), config, |_msg: TransportMessage| {}).await;
+        let reason = tokio::time::timeout(tokio::time::Duration::from_secs(5), client.state_changes().filter_map(|s| async move
+        {
+            match s
+            {
+                ClientState::Disconnected(reason) => Some(reason),
+                _ => None
+            }
+        }).boxed().next()).await.unwrap();
+        assert_eq!(reason, Some(DisconnectReason::HeartbeatTimeout));
+        client.close().await;
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_server_heartbeat()
+    {
+        use futures::StreamExt;
+        logger::StructLogger::initialize_logger();
+        let heartbeat = HeartbeatSettings { interval: tokio::time::Duration::from_millis(200), timeout: tokio::time::Duration::from_millis(200) };
+        let addr = serve::<WsServer, _, _>(ServerConfig { heartbeat: Some(heartbeat), ..Default::default() }, |addr, msg|
+        {
+            async move
+            {
+                WsServer::send(msg, &addr).await;
+            }
+        }).await;
+        //клиент не читает из соединения и не отвечает на ping
+        let stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let id = stream.local_addr().unwrap();
+        let (silent, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert!(WsServer::connection_info(&id).is_some());
+        tokio::time::timeout(tokio::time::Duration::from_secs(3), async
+        {
+            while WsServer::connection_info(&id).is_some()
+            {
+                tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+            }
+        }).await.unwrap();
+        drop(silent);
+        //медленный обработчик клиента задерживает чтение дольше таймаута, но соединение не разрывается
+        let config = ClientConfig { heartbeat: Some(heartbeat), dispatch: Dispatch::Inline, ..Default::default() };
+        let client = HandleClient::start_client_with_config_async(&format!("ws://{}/", addr), config, |_msg: TransportMessage| async
+        {
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        }).await;
+        let mut disconnected = client.state_changes().filter(|s| futures::future::ready(matches!(s, ClientState::Disconnected(_)))).boxed();
+        client.send(TransportMessage::Test2("slow".to_owned())).await;
+        client.send(TransportMessage::Test2("slow".to_owned())).await;
+        assert!(tokio::time::timeout(tokio::time::Duration::from_millis(2500), disconnected.next()).await.is_err());
+        assert!(client.is_connected());
+        client.close().await;
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_failover()
+    {
+        use futures::StreamExt;
+        logger::StructLogger::initialize_logger();
+        //основной сервер пока не запущен, клиент подключается к резервному
+        let backup = serve::<TestServer, _, _>(ServerConfig::default(), |_addr, _msg| async {}).await;
+        let [primary] = local_addrs();
+        let config = ClientConfig
+        {
+            reconnect: ReconnectPolicy { initial_delay: tokio::time::Duration::from_millis(100), jitter: 0.0, ..Default::default() },
+            failover: FailoverSettings { primary_check: tokio::time::Duration::from_millis(300), ..FailoverSettings::new([format!("ws://{}/", backup)]) },
+            ..Default::default()
+        };
+        let client = HandleClient::start_client_with_config(&format!("ws://{}/", primary), config, |_msg: TransportMessage| {}).await;
+        let mut endpoints = client.state_changes().filter_map(|s| async move
+        {
+            match s
+            {
+                ClientState::Connected { endpoint } => Some(endpoint),
+                _ => None
+            }
+        }).boxed();
+        let endpoint = tokio::time::timeout(tokio::time::Duration::from_secs(5), endpoints.next()).await.unwrap();
+        assert_eq!(endpoint, Some(format!("ws://{}/", backup)));
+        //после запуска основного сервера клиент возвращается на него
+        TestServer::start_server(&primary, |_addr, _msg| async {}).await;
+        let endpoint = tokio::time::timeout(tokio::time::Duration::from_secs(5), endpoints.next()).await.unwrap();
+        assert_eq!(endpoint, Some(format!("ws://{}/", primary)));
+        client.close().await;
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_connect()
+    {
+        logger::StructLogger::initialize_logger();
+        let addr = serve::<TestServer, _, _>(ServerConfig::default(), |_addr, _msg| async {}).await;
+        let timeout = tokio::time::Duration::from_secs(5);
+        let (client, headers) = HandleClient::connect(&format!("ws://{}/", addr), ClientConfig::default(), timeout, |_msg: TransportMessage| async {}).await.unwrap();
+        assert!(client.is_connected());
+        assert!(headers.contains_key("sec-websocket-accept"));
+        client.close().await;
+        //сервер не запущен
+        let [stopped] = local_addrs();
+        let result = HandleClient::connect(&format!("ws://{}/", stopped), ClientConfig::default(), timeout, |_msg: TransportMessage| async {}).await;
+        assert!(matches!(result, Err(ConnectError::Failed(_))));
+        //сервер не отвечает на рукопожатие
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let result = HandleClient::connect(&format!("ws://{}/", listener.local_addr().unwrap()), ClientConfig::default(), tokio::time::Duration::from_millis(300), |_msg: TransportMessage| async {}).await;
+        assert!(matches!(result, Err(ConnectError::Timeout)));
+        drop(listener);
+    }
+
+    ///Подключение по wss к серверу с самоподписанным сертификатом __cert__, возвращает соединение и сертификат сервера
+    #[cfg(feature = "tls")]
+    async fn tls_connect(addr: &str, cert: &rcgen::CertifiedKey) -> (tokio_tungstenite::WebSocketStream<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>, Vec<u8>)
+    {
+        use std::sync::Arc;
+        use tokio_rustls::rustls;
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(config)).connect("localhost".try_into().unwrap(), tcp).await.unwrap();
+        let peer = stream.get_ref().1.peer_certificates().unwrap()[0].to_vec();
+        let (ws, _) = tokio_tungstenite::client_async("wss://localhost/", stream).await.unwrap();
+        (ws, peer)
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    pub async fn test_tls_reload()
+    {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+        logger::StructLogger::initialize_logger();
+        let dir = std::env::temp_dir().join("websocket_service_test").join("tls_reload");
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |cert: &rcgen::CertifiedKey|
+        {
+            std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+            std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        };
+        let first = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        write(&first);
+        let tls = crate::ServerTls::from_pem(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+        let config = ServerConfig { tls: Some(tls.clone()), ..Default::default() };
+        let addr = serve::<TestServer, _, _>(config, |_addr, _msg|
+        {
+            async move
+            {
+                TLS_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }).await;
+        let (mut old, peer) = tls_connect(&addr, &first).await;
+        assert_eq!(peer, first.cert.der().to_vec());
+        //после перезагрузки новые подключения получают новый сертификат, старые продолжают работать
+        let second = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        write(&second);
+        tls.reload().unwrap();
+        let (_new, peer) = tls_connect(&addr, &second).await;
+        assert_eq!(peer, second.cert.der().to_vec());
+        let payload = serde_json::to_vec(&TransportMessage::Test2("tls".to_owned())).unwrap();
+        old.send(Message::Binary(payload)).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        assert_eq!(TLS_COUNT.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    ///Сертификат с именем __name__ подписанный __ca__, сертификат и ключ записываются в директорию __dir__
+    #[cfg(feature = "tls")]
+    fn issue(dir: &std::path::Path, name: &str, ca: &rcgen::Certificate, ca_key: &rcgen::KeyPair) -> (std::path::PathBuf, std::path::PathBuf)
+    {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, ca, ca_key).unwrap();
+        let paths = (dir.join(format!("{}.pem", name)), dir.join(format!("{}.key", name)));
+        std::fs::write(&paths.0, cert.pem()).unwrap();
+        std::fs::write(&paths.1, key.serialize_pem()).unwrap();
+        paths
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    pub async fn test_mutual_tls()
+    {
+        use crate::{ClientTls, ServerTls};
+        logger::StructLogger::initialize_logger();
+        let dir = std::env::temp_dir().join("websocket_service_test").join("mtls");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        let (server_cert, server_key) = issue(&dir, "server", &ca, &ca_key);
+        let (client_cert, client_key) = issue(&dir, "sidecar", &ca, &ca_key);
+        let tls = ServerTls::from_pem(server_cert, server_key).unwrap().with_client_ca(dir.join("ca.pem")).unwrap();
+        let addr = serve::<TestServer, _, _>(ServerConfig { tls: Some(tls), ..Default::default() }, |addr, _msg|
+        {
+            async move
+            {
+                let _ = MTLS_SUBJECT.set(TestServer::connection_info(&addr).and_then(|i| i.client_subject));
+            }
+        }).await;
+        let url = format!("wss://localhost:{}/", addr.parse::<std::net::SocketAddr>().unwrap().port());
+        let timeout = tokio::time::Duration::from_secs(5);
+        //без сертификата клиента сервер не принимает подключение
+        let config = ClientConfig { tls: Some(ClientTls::from_pem(dir.join("ca.pem")).unwrap()), ..Default::default() };
+        let result = HandleClient::connect(&url, config, timeout, |_msg: TransportMessage| async {}).await;
+        assert!(result.is_err());
+        let tls = ClientTls::from_pem(dir.join("ca.pem")).unwrap().with_identity(&client_cert, &client_key).unwrap();
+        let (client, _) = HandleClient::connect(&url, ClientConfig { tls: Some(tls), ..Default::default() }, timeout, |_msg: TransportMessage| async {}).await.unwrap();
+        client.try_send(TransportMessage::Test2("mtls".to_owned())).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        assert_eq!(MTLS_SUBJECT.get(), Some(&Some("CN=sidecar".to_owned())));
+        client.close().await;
+        //сертификат сервера не подписан доверенным центром, подключиться можно только без проверки
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(dir.join("other.pem"), other.cert.pem()).unwrap();
+        let tls = ClientTls::from_pem(dir.join("other.pem")).unwrap().with_identity(&client_cert, &client_key).unwrap();
+        let result = HandleClient::connect(&url, ClientConfig { tls: Some(tls), ..Default::default() }, timeout, |_msg: TransportMessage| async {}).await;
+        assert!(matches!(result, Err(ConnectError::Failed(_))));
+        let tls = ClientTls::insecure().with_identity(&client_cert, &client_key).unwrap();
+        let (client, _) = HandleClient::connect(&url, ClientConfig { tls: Some(tls), ..Default::default() }, timeout, |_msg: TransportMessage| async {}).await.unwrap();
+        client.close().await;
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[cfg(unix)]
+    #[test]
+    pub fn test_unix_socket()
+    {
+        use futures::StreamExt;
+        logger::StructLogger::initialize_logger();
+        let path = std::env::temp_dir().join("websocket_service_test").join("unix.sock");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let server = format!("unix:{}", path.display());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async
+        {
+            TestServer::start_server(&server, |addr, msg|
+            {
+                async move
+                {
+                    UNIX_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    TestServer::send(msg, &addr).await;
+                }
+            }).await;
+            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+            let (client, messages) = HandleClient::start_client_stream(&format!("{}:/echo", server), ClientConfig::default()).await;
+            assert!(client.is_connected());
+            client.try_send(TransportMessage::Test2("unix".to_owned())).await.unwrap();
+            let echo = tokio::time::timeout(tokio::time::Duration::from_secs(5), messages.filter(|m| futures::future::ready(matches!(m, TransportMessage::Test2(s) if s == "unix"))).boxed().next()).await.unwrap();
+            assert!(echo.is_some());
+            assert_eq!(UNIX_COUNT.load(std::sync::atomic::Ordering::SeqCst), 1);
+            client.close().await;
+        });
+        assert!(path.exists());
+        //при остановке рантайма сервер останавливается и удаляет файл сокета
+        drop(runtime);
+        assert!(!path.exists());
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_handshake_refresh()
+    {
+        use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+        logger::StructLogger::initialize_logger();
+        //сервер запоминает заголовок авторизации и строку запроса и сразу закрывает соединение
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (requests, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move
+        {
+            while let Ok((stream, _)) = listener.accept().await
+            {
+                let requests = requests.clone();
+                //тип ошибки задан tungstenite
+                #[allow(clippy::result_large_err)]
+                let callback = move |request: &Request, response: Response|
+                {
+                    let auth = request.headers().get("authorization").and_then(|h| h.to_str().ok()).unwrap_or_default().to_owned();
+                    let _ = requests.send((auth, request.uri().query().unwrap_or_default().to_owned()));
+                    Ok(response)
+                };
+                if let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(stream, callback).await
+                {
+                    let _ = ws.close(None).await;
+                }
+            }
+        });
+        static TOKEN: AtomicU32 = AtomicU32::new(0);
+        let config = ClientConfig
+        {
+            reconnect: ReconnectPolicy { initial_delay: tokio::time::Duration::from_millis(100), jitter: 0.0, ..Default::default() },
+            handshake: Some(HandshakeBuilder::new(||
+            {
+                let token = TOKEN.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                Handshake::new().bearer(format!("token-{}", token)).query("tenant", "acme")
+            })),
+            ..Default::default()
+        };
+        let client = HandleClient::start_client_with_config(&format!("ws://{}/", addr), config, |_msg: TransportMessage| {}).await;
+        let timeout = tokio::time::Duration::from_secs(5);
+        //при переподключении используется новый токен
+        assert_eq!(tokio::time::timeout(timeout, received.recv()).await.unwrap(), Some(("Bearer token-1".to_owned(), "tenant=acme".to_owned())));
+        assert_eq!(tokio::time::timeout(timeout, received.recv()).await.unwrap(), Some(("Bearer token-2".to_owned(), "tenant=acme".to_owned())));
+        client.close().await;
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    pub async fn test_routes()
+    {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::{Error, Message};
+        use crate::Router;
+        logger::StructLogger::initialize_logger();
+        let [addr] = local_addrs();
+        Router::new()
+            .route(ChatServer::endpoint("/chat", |addr, msg: TransportMessage|
+            {
+                async move
+                {
+                    ChatServer::message_to_all_except_sender(&addr, msg).await;
+                }
+            }))
+            .route(ChatServer::endpoint("/lobby", |_addr, msg: TransportMessage|
+            {
+                async move
+                {
+                    ChatServer::broadcast_to_endpoint("lobby", msg).await;
+                }
+            }))
+            .route(ChatServer::endpoint("/chat/telemetry", |_addr, msg: TelemetryMessage|
+            {
+                async move
+                {
+                    ChatServer::broadcast_message_to_all(msg).await;
+                }
+            }))
+            .route(TelemetryServer::endpoint("telemetry", |_addr, msg: TelemetryMessage|
+            {
+                async move
+                {
+                    TELEMETRY_CPU.store(msg.cpu, std::sync::atomic::Ordering::SeqCst);
+                    //рассылка телеметрии не доходит до клиентов чата
+                    TelemetryServer::broadcast_message_to_all(msg).await;
+                }
+            }))
+            .start(&addr, ServerConfig::default())
+            .await;
+        listening(&addr).await;
+        match tokio_tungstenite::connect_async(format!("ws://{}/unknown", addr)).await
+        {
+            Err(Error::Http(response)) => assert_eq!(response.status(), 404),
+            other => panic!("Ожидался ответ 404, получено {:?}", other.map(|(_, r)| r.status()))
+        }
+        let (mut chat_sender, _) = tokio_tungstenite::connect_async(format!("ws://{}/chat", addr)).await.unwrap();
+        let (mut chat_receiver, _) = tokio_tungstenite::connect_async(format!("ws://{}/chat", addr)).await.unwrap();
+        let (mut telemetry, _) = tokio_tungstenite::connect_async(format!("ws://{}/telemetry", addr)).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        let timeout = tokio::time::Duration::from_secs(3);
+        telemetry.send(Message::Binary(serde_json::to_vec(&TelemetryMessage { cpu: 42 }).unwrap())).await.unwrap();
+        let echo = tokio::time::timeout(timeout, telemetry.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(serde_json::from_slice::<TelemetryMessage>(&echo.into_data()).unwrap(), TelemetryMessage { cpu: 42 });
+        assert_eq!(TELEMETRY_CPU.load(std::sync::atomic::Ordering::SeqCst), 42);
+        chat_sender.send(Message::Binary(serde_json::to_vec(&TransportMessage::Test2("чат".to_owned())).unwrap())).await.unwrap();
+        let chat = tokio::time::timeout(timeout, chat_receiver.next()).await.unwrap().unwrap().unwrap();
+        assert!(matches!(serde_json::from_slice::<TransportMessage>(&chat.into_data()).unwrap(), TransportMessage::Test2(s) if s == "чат"));
+        //сообщения чата не попадают в телеметрию, а телеметрия в чат
+        assert!(tokio::time::timeout(tokio::time::Duration::from_millis(500), telemetry.next()).await.is_err());
+        assert!(tokio::time::timeout(tokio::time::Duration::from_millis(500), chat_sender.next()).await.is_err());
+        //у каждой точки подключения одного сервера свой список клиентов, в том числе для разных типов сообщений
+        let (mut lobby, _) = tokio_tungstenite::connect_async(format!("ws://{}/lobby", addr)).await.unwrap();
+        let (mut chat_telemetry, _) = tokio_tungstenite::connect_async(format!("ws://{}/chat/telemetry", addr)).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        lobby.send(Message::Binary(serde_json::to_vec(&TransportMessage::Test2("лобби".to_owned())).unwrap())).await.unwrap();
+        let echo = tokio::time::timeout(timeout, lobby.next()).await.unwrap().unwrap().unwrap();
+        assert!(matches!(serde_json::from_slice::<TransportMessage>(&echo.into_data()).unwrap(), TransportMessage::Test2(s) if s == "лобби"));
+        chat_telemetry.send(Message::Binary(serde_json::to_vec(&TelemetryMessage { cpu: 7 }).unwrap())).await.unwrap();
+        let echo = tokio::time::timeout(timeout, chat_telemetry.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(serde_json::from_slice::<TelemetryMessage>(&echo.into_data()).unwrap(), TelemetryMessage { cpu: 7 });
+        assert!(tokio::time::timeout(tokio::time::Duration::from_millis(500), chat_receiver.next()).await.is_err());
+        assert!(tokio::time::timeout(tokio::time::Duration::from_millis(500), telemetry.next()).await.is_err());
+        assert!(tokio::time::timeout(tokio::time::Duration::from_millis(100), chat_telemetry.next()).await.is_err());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    pub async fn test_endpoint_broadcast()
+    {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+        use crate::Router;
+        logger::StructLogger::initialize_logger();
+        let [addr] = local_addrs();
+        let room = |_addr, msg: TransportMessage| async move
+        {
+            RoomServer::broadcast_message_to_all(msg).await;
+        };
+        Router::new()
+            .route(RoomServer::endpoint("/red", room))
+            .route(RoomServer::endpoint("/blue", room))
+            .start(&addr, ServerConfig::default())
+            .await;
+        listening(&addr).await;
+        let (mut red, _) = tokio_tungstenite::connect_async(format!("ws://{}/red", addr)).await.unwrap();
+        let (mut blue, _) = tokio_tungstenite::connect_async(format!("ws://{}/blue", addr)).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        let timeout = tokio::time::Duration::from_secs(3);
+        //рассылка из обработчика /red доходит только до клиентов /red
+        red.send(Message::Binary(serde_json::to_vec(&TransportMessage::Test2("red".to_owned())).unwrap())).await.unwrap();
+        let echo = tokio::time::timeout(timeout, red.next()).await.unwrap().unwrap().unwrap();
+        assert!(matches!(serde_json::from_slice::<TransportMessage>(&echo.into_data()).unwrap(), TransportMessage::Test2(s) if s == "red"));
+        assert!(tokio::time::timeout(tokio::time::Duration::from_millis(500), blue.next()).await.is_err());
+        //вне обработчика клиентам точки подключения можно написать только через broadcast_to_endpoint
+        RoomServer::broadcast_message_to_all(TransportMessage::Test2("nobody".to_owned())).await;
+        RoomServer::broadcast_to_endpoint("/blue", TransportMessage::Test2("blue".to_owned())).await;
+        let echo = tokio::time::timeout(timeout, blue.next()).await.unwrap().unwrap().unwrap();
+        assert!(matches!(serde_json::from_slice::<TransportMessage>(&echo.into_data()).unwrap(), TransportMessage::Test2(s) if s == "blue"));
+        assert!(tokio::time::timeout(tokio::time::Duration::from_millis(500), red.next()).await.is_err());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    pub async fn test_origin_policy()
+    {
+        use futures::StreamExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Error};
+        use crate::{OriginPolicy, SecurityEvent};
+        logger::StructLogger::initialize_logger();
+        let mut events = crate::security_events();
+        let config = ServerConfig { origins: Some(OriginPolicy::new(["https://app.example.com", "https://*.example.org"])), ..Default::default() };
+        let addr = serve::<TestServer, _, _>(config, |_addr, _msg| async {}).await;
+        let connect = |origin: &str|
+        {
+            let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+            request.headers_mut().insert("origin", origin.parse().unwrap());
+            tokio_tungstenite::connect_async(request)
+        };
+        assert!(connect("https://app.example.com").await.is_ok());
+        assert!(connect("https://chat.example.org").await.is_ok());
+        //клиенты без заголовка Origin по умолчанию разрешены
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}/", addr)).await.is_ok());
+        match connect("https://evil.com").await
+        {
+            Err(Error::Http(response)) => assert_eq!(response.status(), 403),
+            other => panic!("Ожидался ответ 403, получено {:?}", other.map(|(_, r)| r.status()))
+        }
+        let event = tokio::time::timeout(tokio::time::Duration::from_secs(3), events.next()).await.unwrap().unwrap();
+        assert!(matches!(event, SecurityEvent::OriginRejected { origin: Some(o), .. } if o == "https://evil.com"));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    pub async fn test_rate_limits()
+    {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message};
+        use crate::{LimitAction, RateLimits, SecurityEvent};
+        logger::StructLogger::initialize_logger();
+        let mut events = crate::security_events().filter(|e| futures::future::ready(!matches!(e, SecurityEvent::OriginRejected { .. })));
+        let limits = RateLimits { messages_per_second: Some(5), max_connections_per_ip: Some(2), action: LimitAction::Notify, ..Default::default() };
+        let notify = serve::<TestServer, _, _>(ServerConfig { limits: Some(limits), ..Default::default() }, |_addr, _msg|
+        {
+            async move
+            {
+                LIMIT_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }).await;
+        let limits = RateLimits { bytes_per_second: Some(1024), action: LimitAction::Close, ..Default::default() };
+        let close = serve::<TestServer, _, _>(ServerConfig { limits: Some(limits), ..Default::default() }, |_addr, _msg| async {}).await;
+        let timeout = tokio::time::Duration::from_secs(3);
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", notify)).await.unwrap();
+        for _ in 0..20
+        {
+            ws.send(Message::Binary(serde_json::to_vec(&TransportMessage::Test2("limit".to_owned())).unwrap())).await.unwrap();
+        }
+        //лишние сообщения отброшены, клиент получил одно уведомление
+        let notice = tokio::time::timeout(timeout, ws.next()).await.unwrap().unwrap().unwrap().into_data();
+        assert!(crate::limits::is_notice(&notice));
+        assert!(matches!(tokio::time::timeout(timeout, events.next()).await.unwrap(), Some(SecurityEvent::RateLimited { action: LimitAction::Notify, .. })));
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        assert!((5..=7).contains(&LIMIT_COUNT.load(std::sync::atomic::Ordering::SeqCst)));
+        //третье подключение с того же ip закрывается без рукопожатия
+        let (second, _) = tokio_tungstenite::connect_async(format!("ws://{}/", notify)).await.unwrap();
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}/", notify)).await.is_err());
+        assert!(matches!(tokio::time::timeout(timeout, events.next()).await.unwrap(), Some(SecurityEvent::TooManyConnections { .. })));
+        drop(second);
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}/", notify)).await.is_ok());
+        //превышение скорости в байтах закрывает соединение с кодом 1008
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", close)).await.unwrap();
+        ws.send(Message::Binary(vec![b'0'; 1024])).await.unwrap();
+        ws.send(Message::Binary(vec![b'0'; 1024])).await.unwrap();
+        match tokio::time::timeout(timeout, ws.next()).await.unwrap()
+        {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+            other => panic!("Ожидалось закрытие соединения с кодом 1008, получено {:?}", other)
+        }
+        //клиент не отвечает на фрейм закрытия, сервер разрывает соединение не дожидаясь ответа
+        silent_close::<TestServer>(format!("ws://{}/", close), vec![Message::Binary(vec![b'0'; 1024]), Message::Binary(vec![b'0'; 1024])]).await;
+    }
+
+    ///Клиент отправляет серверу __S__ фреймы __frames__ после которых сервер закрывает соединение,
+    /// но не отвечает на фрейм закрытия, сервер должен разорвать соединение не дожидаясь ответа
+    #[cfg(feature = "server")]
+    async fn silent_close<S: Server<TransportMessage>>(request: impl tokio_tungstenite::tungstenite::client::IntoClientRequest, frames: Vec<tokio_tungstenite::tungstenite::Message>)
+    {
+        use futures::SinkExt;
+        let request = request.into_client_request().unwrap();
+        let addr = format!("{}:{}", request.uri().host().unwrap(), request.uri().port_u16().unwrap());
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let id = stream.local_addr().unwrap();
+        let (mut silent, _) = tokio_tungstenite::client_async(request, stream).await.unwrap();
+        for frame in frames
+        {
+            silent.send(frame).await.unwrap();
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        assert!(S::connection_info(&id).is_some());
+        tokio::time::timeout(tokio::time::Duration::from_secs(3), async
+        {
+            while S::connection_info(&id).is_some()
+            {
+                tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+            }
+        }).await.unwrap();
+        drop(silent);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    pub async fn test_ip_filter()
+    {
+        use crate::IpFilter;
+        logger::StructLogger::initialize_logger();
+        let filter = IpFilter::new();
+        let addr = serve::<TestServer, _, _>(ServerConfig { ip_filter: Some(filter.clone()), ..Default::default() }, |_addr, _msg| async {}).await;
+        filter.deny("127.0.0.0/8").unwrap();
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}/", addr)).await.is_err());
+        assert_eq!(filter.rejected(), 1);
+        //списки меняются без перезапуска сервера
+        filter.remove("127.0.0.0/8").unwrap();
+        filter.allow("127.0.0.1").unwrap();
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}/", addr)).await.is_ok());
+        filter.set_allow(["10.0.0.0/8"]).unwrap();
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}/", addr)).await.is_err());
+        assert_eq!(filter.rejected(), 2);
+    }
+
+    ///Токен HS256 с секретом `secret` действующий __ttl__ секунд
+    #[cfg(feature = "jwt")]
+    fn jwt(subject: &str, n: u32, ttl: i64) -> String
+    {
+        let exp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64 + ttl;
+        let claims = serde_json::json!({ "sub": subject, "n": n, "exp": exp });
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &jsonwebtoken::EncodingKey::from_secret(b"secret")).unwrap()
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[cfg(feature = "jwt")]
+    #[tokio::test]
+    pub async fn test_jwt_auth()
+    {
+        use futures::StreamExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, protocol::frame::coding::CloseCode, Error, Message};
+        use crate::{ExpiryAction, JwtAuth};
+        logger::StructLogger::initialize_logger();
+        let dir = std::env::temp_dir().join("websocket_service_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let jwks = dir.join("auth.jwks");
+        std::fs::write(&jwks, r#"{"keys":[{"kty":"oct","alg":"HS256","k":"c2VjcmV0"}]}"#).unwrap();
+        let auth = JwtAuth::from_jwks(&jwks).unwrap().on_expiry(ExpiryAction::Reauthenticate { grace: tokio::time::Duration::from_secs(2) });
+        //сервер отвечает номером токена с которым сейчас работает клиент
+        let reauth = serve::<TestServer, _, _>(ServerConfig { auth: Some(auth), ..Default::default() }, |addr, _msg|
+        {
+            async move
+            {
+                let claims = TestServer::connection_info(&addr).and_then(|i| i.claims).unwrap();
+                TestServer::send(TransportMessage::Test2(claims["n"].to_string()), &addr).await;
+            }
+        }).await;
+        let expiring = serve::<TestServer, _, _>(ServerConfig { auth: Some(JwtAuth::from_jwks(&jwks).unwrap()), ..Default::default() }, |_addr, _msg| async {}).await;
+        match tokio_tungstenite::connect_async(format!("ws://{}/", reauth)).await
+        {
+            Err(Error::Http(response)) => assert_eq!(response.status(), 401),
+            other => panic!("Ожидался ответ 401, получено {:?}", other.map(|(_, r)| r.status()))
+        }
+        let mut request = format!("ws://{}/", reauth).into_client_request().unwrap();
+        request.headers_mut().insert("authorization", format!("Bearer {}", jwt("user", 0, -10)).parse().unwrap());
+        assert!(tokio_tungstenite::connect_async(request).await.is_err());
+        //когда срок действия токена истекает клиент получает новый токен из handshake и отправляет его без переподключения
+        static ISSUED: AtomicU32 = AtomicU32::new(0);
+        let config = ClientConfig
+        {
+            handshake: Some(HandshakeBuilder::new(|| Handshake::new().bearer(jwt("user", ISSUED.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1, 2)))),
+            ..Default::default()
+        };
+        let (client, messages) = HandleClient::start_client_stream(&format!("ws://{}/", reauth), config).await;
+        let mut messages = messages.filter_map(|m| futures::future::ready(match m { TransportMessage::Test2(n) if n.len() == 1 => Some(n), _ => None }));
+        let timeout = tokio::time::Duration::from_secs(5);
+        client.try_send(TransportMessage::Test2("claims".to_owned())).await.unwrap();
+        assert_eq!(tokio::time::timeout(timeout, messages.next()).await.unwrap().as_deref(), Some("1"));
+        tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+        assert!(client.is_connected());
+        client.try_send(TransportMessage::Test2("claims".to_owned())).await.unwrap();
+        assert_eq!(tokio::time::timeout(timeout, messages.next()).await.unwrap().as_deref(), Some("2"));
+        //токен можно обновить вручную
+        client.refresh_token(jwt("user", 7, 60)).unwrap();
+        client.try_send(TransportMessage::Test2("claims".to_owned())).await.unwrap();
+        assert_eq!(tokio::time::timeout(timeout, messages.next()).await.unwrap().as_deref(), Some("7"));
+        assert_eq!(ISSUED.load(std::sync::atomic::Ordering::SeqCst), 2);
+        client.close().await;
+        //без повторной авторизации соединение закрывается когда истекает срок действия токена
+        let mut request = format!("ws://{}/?access_token=", expiring);
+        request.push_str(&jwt("user", 1, 1));
+        let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        match tokio::time::timeout(timeout, ws.next()).await.unwrap()
+        {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+            other => panic!("Ожидалось закрытие соединения с кодом 1008, получено {:?}", other)
+        }
+        //новый токен не прошел проверку, клиент не отвечает на фрейм закрытия
+        let mut request = format!("ws://{}/", reauth).into_client_request().unwrap();
+        request.headers_mut().insert("authorization", format!("Bearer {}", jwt("user", 1, 60)).parse().unwrap());
+        silent_close::<TestServer>(request, vec![crate::auth::encode(&crate::auth::AuthFrame::Token(jwt("user", 1, -10)))]).await;
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[cfg(feature = "seal")]
+    #[tokio::test]
+    pub async fn test_sealing()
+    {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::{http::HeaderMap, protocol::frame::coding::CloseCode, Error, Message};
+        use crate::{Encryption, SecurityEvent, Sealing};
+        logger::StructLogger::initialize_logger();
+        let mut events = crate::security_events().filter(|e| futures::future::ready(match e
+        {
+            SecurityEvent::FrameRejected { .. } => true,
+            SecurityEvent::AuthFailed { reason, .. } => reason.starts_with("Ошибка обмена ключами"),
+            _ => false
+        }));
+        let sealing = Sealing::hmac(b"relay secret").encrypt(Encryption::Exchange);
+        let sealed = serve::<TestServer, _, _>(ServerConfig { sealing: Some(sealing.clone()), ..Default::default() }, |addr, msg|
+        {
+            async move
+            {
+                TestServer::send(msg, &addr).await;
+            }
+        }).await;
+        let signed = Sealing::hmac(b"relay secret");
+        let relay = serve::<TestServer, _, _>(ServerConfig { sealing: Some(signed.clone()), ..Default::default() }, |_addr, _msg|
+        {
+            async move
+            {
+                SEAL_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }).await;
+        let timeout = tokio::time::Duration::from_secs(3);
+        //клиент с теми же ключами обменивается зашифрованными сообщениями
+        let config = ClientConfig { sealing: Some(sealing), ..Default::default() };
+        let (client, mut messages) = HandleClient::start_client_stream(&format!("ws://{}/", sealed), config).await;
+        client.try_send(TransportMessage::Test2("sealed".to_owned())).await.unwrap();
+        match tokio::time::timeout(timeout, messages.next()).await.unwrap()
+        {
+            Some(TransportMessage::Test2(s)) => assert_eq!(s, "sealed"),
+            other => panic!("Ожидалось эхо сообщения, получено {:?}", other)
+        }
+        client.close().await;
+        //без подписанного открытого ключа рукопожатие отклоняется
+        match tokio_tungstenite::connect_async(format!("ws://{}/", sealed)).await
+        {
+            Err(Error::Http(response)) => assert_eq!(response.status(), 401),
+            other => panic!("Ожидался ответ 401, получено {:?}", other.map(|(_, r)| r.status()))
+        }
+        assert!(matches!(tokio::time::timeout(timeout, events.next()).await.unwrap(), Some(SecurityEvent::AuthFailed { .. })));
+        //неподписанный фрейм не доходит до обработчика, соединение закрывается с кодом 1008
+        let payload = serde_json::to_vec(&TransportMessage::Test2("relay".to_owned())).unwrap();
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", relay)).await.unwrap();
+        ws.send(Message::Binary(payload.clone())).await.unwrap();
+        match tokio::time::timeout(timeout, ws.next()).await.unwrap()
+        {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+            other => panic!("Ожидалось закрытие соединения с кодом 1008, получено {:?}", other)
+        }
+        assert!(matches!(tokio::time::timeout(timeout, events.next()).await.unwrap(), Some(SecurityEvent::FrameRejected { .. })));
+        silent_close::<TestServer>(format!("ws://{}/", relay), vec![Message::Binary(payload.clone())]).await;
+        assert!(matches!(tokio::time::timeout(timeout, events.next()).await.unwrap(), Some(SecurityEvent::FrameRejected { .. })));
+        //повторно отправленный подписанный фрейм отклоняется, обработчик вызван один раз
+        let (mut sealer, _) = signed.offer().0.finish(&signed, &HeaderMap::new()).unwrap();
+        let frame = sealer.seal(Message::Binary(payload));
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", relay)).await.unwrap();
+        ws.send(frame.clone()).await.unwrap();
+        ws.send(frame).await.unwrap();
+        match tokio::time::timeout(timeout, ws.next()).await.unwrap()
+        {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+            other => panic!("Ожидалось закрытие соединения с кодом 1008, получено {:?}", other)
+        }
+        assert!(matches!(tokio::time::timeout(timeout, events.next()).await.unwrap(), Some(SecurityEvent::FrameRejected { reason, .. }) if reason == crate::SealError::Replayed.to_string()));
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        assert_eq!(SEAL_COUNT.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[cfg(feature = "seal")]
+    #[tokio::test]
+    pub async fn test_sealed_failover()
+    {
+        use futures::StreamExt;
+        use crate::{Encryption, Sealing};
+        logger::StructLogger::initialize_logger();
+        let [primary, backup] = local_addrs();
+        let sealing = Sealing::hmac(b"failover secret").encrypt(Encryption::Exchange);
+        let config = ServerConfig { sealing: Some(sealing.clone()), ..Default::default() };
+        TestServer::start_server_with_config(&backup, config.clone(), |_addr, _msg: TransportMessage| async {}).await;
+        let config = ClientConfig
+        {
+            reconnect: ReconnectPolicy { initial_delay: tokio::time::Duration::from_millis(100), jitter: 0.0, ..Default::default() },
+            failover: FailoverSettings { primary_check: tokio::time::Duration::from_millis(300), ..FailoverSettings::new([format!("ws://{}/", backup)]) },
+            sealing: Some(sealing),
+            ..Default::default()
+        };
+        let client = HandleClient::start_client_with_config(&format!("ws://{}/", primary), config.clone(), |_msg: TransportMessage| {}).await;
+        let mut endpoints = client.state_changes().filter_map(|s| async move
+        {
+            match s
+            {
+                ClientState::Connected { endpoint } => Some(endpoint),
+                _ => None
+            }
+        }).boxed();
+        let endpoint = tokio::time::timeout(tokio::time::Duration::from_secs(5), endpoints.next()).await.unwrap();
+        assert_eq!(endpoint, Some(format!("ws://{}/", backup)));
+        //проверка основного сервера выполняет обмен ключами так же как подключение
+        TestServer::start_server_with_config(&primary, ServerConfig { sealing: config.sealing, ..Default::default() }, |_addr, _msg: TransportMessage| async {}).await;
+        let endpoint = tokio::time::timeout(tokio::time::Duration::from_secs(5), endpoints.next()).await.unwrap();
+        assert_eq!(endpoint, Some(format!("ws://{}/", primary)));
+        client.close().await;
+    }
+
+    #[cfg(feature = "server")]
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    pub async fn test_client_handles()
+    {
+        logger::StructLogger::initialize_logger();
+        let addr = serve::<TestServer, _, _>(ServerConfig::default(), |_addr, _msg|
+        {
+            async move
+            {
+                HANDLE_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }).await;
+        //два независимых экземпляра одного типа клиента
+        let first = HandleClient::start_client(&format!("ws://{}/", addr), |_msg: TransportMessage| {}).await;
+        let second = HandleClient::start_client(&format!("ws://{}/", addr), |_msg: TransportMessage| {}).await;
+        assert!(first.is_connected() && second.is_connected());
+        first.clone().send(TransportMessage::Test2("first".to_owned())).await;
+        second.send(TransportMessage::Test2("second".to_owned())).await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        assert_eq!(HANDLE_COUNT.load(std::sync::atomic::Ordering::SeqCst), 2);
+        first.close().await;
+        assert_eq!(*first.state().borrow(), ClientState::Closed);
+        assert!(!first.is_connected());
+        assert!(second.is_connected());
+        second.close().await;
+    }
+
     //#[tokio::test]
     ///json -> 1000 итераций теста завершено за:  23.642501ms, 18.955071ms, 18.682914ms, 18.905361ms, 
     ///bin ->  1000 итераций теста завершено за: 20.686414ms, 19.505297ms, 18.415195ms, 17.314488ms, 17.15283ms, 17.544082ms, 
@@ -170,6 +1586,7 @@ mod test
     #[cfg(feature = "server")]
     #[cfg(feature = "client")]
     #[tokio::test]
+    #[allow(clippy::unused_unit, clippy::empty_line_after_outer_attr)]
     pub async fn test_many_clients()
     {
         //поток заканчивал работу прежде чем успевал получить сообщения от клиента и сервера!
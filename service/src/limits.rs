@@ -0,0 +1,234 @@
+use std::time::Duration;
+#[cfg(feature = "server")]
+use tokio::time::Instant;
+#[cfg(feature = "server")]
+use tokio_tungstenite::tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame}, Message};
+
+#[cfg(feature = "server")]
+///Что делать с сообщением клиента превысившего ограничение скорости
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitAction
+{
+    ///сообщение отбрасывается
+    #[default]
+    Drop,
+    ///сообщение отбрасывается, клиенту отправляется уведомление с временем через которое можно продолжить отправку
+    Notify,
+    ///соединение закрывается с кодом 1008 (нарушение политики)
+    Close
+}
+
+///Ограничения входящего трафика сервера
+/// __messages_per_second__ сообщений в секунду от одного соединения
+/// __bytes_per_second__ байт в секунду от одного соединения, учитываются все фреймы в том числе фрагменты и файлы
+/// __max_connections_per_ip__ одновременных подключений с одного ip адреса, лишние подключения закрываются сразу после приема
+/// __action__ что делать при превышении скорости
+///
+/// скорость ограничивается по алгоритму token bucket: соединение может отправить пачку размером с секундный лимит,
+/// после чего лимит восстанавливается равномерно
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits
+{
+    pub messages_per_second: Option<u32>,
+    pub bytes_per_second: Option<u64>,
+    pub max_connections_per_ip: Option<usize>,
+    pub action: LimitAction
+}
+
+///Маркер уведомления об ограничении скорости, формат: маркер, через сколько мс можно продолжить отправку (u32 be)
+const NOTICE_MARKER: u8 = 0xB7;
+
+pub(crate) fn is_notice(data: &[u8]) -> bool
+{
+    data.first() == Some(&NOTICE_MARKER)
+}
+#[cfg(feature = "server")]
+fn notice(retry_after: Duration) -> Message
+{
+    let mut message = vec![NOTICE_MARKER];
+    message.extend_from_slice(&(retry_after.as_millis().min(u32::MAX as u128) as u32).to_be_bytes());
+    Message::Binary(message)
+}
+///Через сколько можно продолжить отправку по уведомлению сервера
+pub(crate) fn retry_after(data: &[u8]) -> Option<Duration>
+{
+    let millis: [u8; 4] = data.get(1..5)?.try_into().ok()?;
+    Some(Duration::from_millis(u32::from_be_bytes(millis) as u64))
+}
+
+#[cfg(feature = "server")]
+struct Bucket
+{
+    capacity: f64,
+    tokens: f64,
+    ///пополнение в секунду
+    rate: f64,
+    updated: Instant,
+    ///лимит этой корзины уже превышен, о повторных превышениях не сообщается
+    throttled: bool
+}
+#[cfg(feature = "server")]
+impl Bucket
+{
+    fn new(rate: f64) -> Self
+    {
+        Self
+        {
+            capacity: rate,
+            tokens: rate,
+            rate,
+            updated: Instant::now(),
+            throttled: false
+        }
+    }
+    fn refill(&mut self)
+    {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+    ///Хватает ли токенов на __amount__, сообщение больше емкости проходит когда корзина полная
+    fn available(&self, amount: f64) -> bool
+    {
+        self.tokens >= amount.min(self.capacity)
+    }
+    fn take(&mut self, amount: f64)
+    {
+        self.tokens -= amount;
+    }
+    ///Через сколько наберется __amount__ токенов
+    fn wait(&self, amount: f64) -> Duration
+    {
+        Duration::from_secs_f64(((amount.min(self.capacity) - self.tokens) / self.rate).max(0.0))
+    }
+}
+
+///Превышение ограничения скорости
+#[cfg(feature = "server")]
+pub(crate) struct Violation
+{
+    pub action: LimitAction,
+    ///первое превышение после периода нормальной скорости, только о нем сообщается клиенту и в событиях безопасности
+    pub first: bool,
+    pub retry_after: Duration
+}
+#[cfg(feature = "server")]
+impl Violation
+{
+    ///Фрейм который нужно отправить клиенту: уведомление только при первом превышении или фрейм закрытия
+    pub fn reply(&self) -> Option<Message>
+    {
+        match self.action
+        {
+            LimitAction::Drop => None,
+            LimitAction::Notify => self.first.then(|| notice(self.retry_after)),
+            LimitAction::Close => Some(Message::Close(Some(CloseFrame
+            {
+                code: CloseCode::Policy,
+                reason: "Превышено ограничение скорости".into()
+            })))
+        }
+    }
+}
+
+///Ограничение скорости одного соединения
+#[cfg(feature = "server")]
+pub(crate) struct Limiter
+{
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    action: LimitAction
+}
+#[cfg(feature = "server")]
+impl Limiter
+{
+    pub fn new(limits: Option<&RateLimits>) -> Self
+    {
+        Self
+        {
+            messages: limits.and_then(|l| l.messages_per_second).map(|r| Bucket::new(r as f64)),
+            bytes: limits.and_then(|l| l.bytes_per_second).map(|r| Bucket::new(r as f64)),
+            action: limits.map(|l| l.action).unwrap_or_default()
+        }
+    }
+    ///Входящий фрейм размером __len__ байт
+    pub fn frame(&mut self, len: usize) -> Option<Violation>
+    {
+        Self::check(self.bytes.as_mut(), len as f64, self.action)
+    }
+    ///Входящее сообщение
+    pub fn message(&mut self) -> Option<Violation>
+    {
+        Self::check(self.messages.as_mut(), 1.0, self.action)
+    }
+    fn check(bucket: Option<&mut Bucket>, amount: f64, action: LimitAction) -> Option<Violation>
+    {
+        let bucket = bucket?;
+        bucket.refill();
+        if bucket.available(amount)
+        {
+            bucket.take(amount);
+            bucket.throttled = false;
+            return None;
+        }
+        Some(Violation
+        {
+            action,
+            first: !std::mem::replace(&mut bucket.throttled, true),
+            retry_after: bucket.wait(amount)
+        })
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests
+{
+    use std::time::Duration;
+    use super::{is_notice, retry_after, LimitAction, Limiter, RateLimits};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limiter()
+    {
+        let limits = RateLimits { messages_per_second: Some(2), bytes_per_second: Some(100), action: LimitAction::Notify, ..Default::default() };
+        let mut limiter = Limiter::new(Some(&limits));
+        assert!(limiter.message().is_none());
+        assert!(limiter.message().is_none());
+        let violation = limiter.message().unwrap();
+        assert!(violation.first);
+        assert_eq!(violation.retry_after, Duration::from_millis(500));
+        let notice = violation.reply().unwrap().into_data();
+        assert!(is_notice(&notice));
+        assert_eq!(retry_after(&notice), Some(Duration::from_millis(500)));
+        //повторное превышение без уведомления
+        assert!(limiter.message().unwrap().reply().is_none());
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.message().is_none());
+        //сообщение больше секундного лимита проходит когда лимит полностью восстановлен,
+        //после него следующие фреймы ждут пока не наберется его размер
+        assert!(limiter.frame(500).is_none());
+        assert_eq!(limiter.frame(1).unwrap().retry_after, Duration::from_millis(4010));
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert!(limiter.frame(1).is_some());
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(limiter.frame(1).is_none());
+        //без ограничений все проходит
+        let mut limiter = Limiter::new(None);
+        assert!((0..1000).all(|_| limiter.message().is_none() && limiter.frame(1 << 20).is_none()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limiter_message_notice()
+    {
+        //фреймы проходят по лимиту байт, уведомление о превышении лимита сообщений отправляется один раз
+        let limits = RateLimits { messages_per_second: Some(1), bytes_per_second: Some(1000), action: LimitAction::Notify, ..Default::default() };
+        let mut limiter = Limiter::new(Some(&limits));
+        assert!(limiter.frame(10).is_none() && limiter.message().is_none());
+        let first: Vec<bool> = (0..5).map(|_|
+        {
+            assert!(limiter.frame(10).is_none());
+            limiter.message().unwrap().first
+        }).collect();
+        assert_eq!(first, [true, false, false, false, false]);
+    }
+}
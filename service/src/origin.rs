@@ -0,0 +1,85 @@
+
+///Список источников (заголовок `Origin`) с которых браузер может подключаться к серверу
+/// __origins__ допустимые источники:
+/// - точный источник `https://app.example.com` или `http://localhost:8080`
+/// - все поддомены `https://*.example.com` (сам `https://example.com` нужно указать отдельно)
+/// - `null` для страниц открытых из файла или песочницы
+///
+/// __allow_missing__ разрешать подключения без заголовка `Origin`, его не отправляют не браузерные клиенты
+/// (в том числе клиент этой библиотеки), по умолчанию разрешены
+#[derive(Debug, Clone)]
+pub struct OriginPolicy
+{
+    pub origins: Vec<String>,
+    pub allow_missing: bool
+}
+impl OriginPolicy
+{
+    pub fn new<I, S>(origins: I) -> Self where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        Self
+        {
+            origins: origins.into_iter().map(|o| normalize(&o.into())).collect(),
+            allow_missing: true
+        }
+    }
+    ///Разрешено ли подключение с источника __origin__, регистр и завершающий `/` не учитываются
+    pub fn allows(&self, origin: Option<&str>) -> bool
+    {
+        match origin
+        {
+            Some(origin) =>
+            {
+                let origin = normalize(origin);
+                self.origins.iter().any(|rule| matches(&normalize(rule), &origin))
+            },
+            None => self.allow_missing
+        }
+    }
+}
+
+fn normalize(origin: &str) -> String
+{
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
+
+fn matches(rule: &str, origin: &str) -> bool
+{
+    match rule.split_once("://*.")
+    {
+        Some((scheme, domain)) => origin.strip_prefix(scheme)
+            .and_then(|o| o.strip_prefix("://"))
+            .and_then(|host| host.strip_suffix(domain))
+            .and_then(|sub| sub.strip_suffix('.'))
+            .is_some_and(|sub| !sub.is_empty() && sub.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')),
+        None => rule == origin
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::OriginPolicy;
+
+    #[test]
+    fn test_allows()
+    {
+        let policy = OriginPolicy::new(["https://app.example.com", "https://*.example.org", "http://localhost:8080/", "null"]);
+        assert!(policy.allows(Some("https://app.example.com")));
+        assert!(policy.allows(Some("HTTPS://App.Example.com/")));
+        assert!(!policy.allows(Some("http://app.example.com")));
+        assert!(!policy.allows(Some("https://app.example.com.evil.com")));
+        assert!(policy.allows(Some("https://a.example.org")));
+        assert!(policy.allows(Some("https://a.b.example.org")));
+        assert!(!policy.allows(Some("https://example.org")));
+        assert!(!policy.allows(Some("https://evilexample.org")));
+        assert!(!policy.allows(Some("https://a.example.org:8443")));
+        assert!(!policy.allows(Some("https://a/b.example.org")));
+        assert!(policy.allows(Some("http://localhost:8080")));
+        assert!(!policy.allows(Some("http://localhost:8081")));
+        assert!(policy.allows(Some("null")));
+        assert!(policy.allows(None));
+        assert!(!OriginPolicy { allow_missing: false, ..OriginPolicy::new(["https://app.example.com"]) }.allows(None));
+        assert!(!OriginPolicy::new(["https://app.example.com"]).allows(Some("null")));
+    }
+}
@@ -0,0 +1,289 @@
+use std::{collections::VecDeque, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use logger::{error, warn};
+use tokio::{fs::{File, OpenOptions}, io::AsyncWriteExt};
+use crate::transport::Outbound;
+
+///Настройки очереди сообщений отправленных пока клиент не подключен к серверу
+/// __capacity__ максимальное количество сообщений в очереди, при переполнении удаляются самые старые
+/// __ttl__ сколько хранить сообщение, устаревшие сообщения не отправляются
+/// __path__ файл в котором хранится очередь, если задан то очередь переживает перезапуск процесса
+#[derive(Debug, Clone)]
+pub struct OutboxSettings
+{
+    pub capacity: usize,
+    pub ttl: Option<Duration>,
+    pub path: Option<PathBuf>
+}
+impl Default for OutboxSettings
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            capacity: 10_000,
+            ttl: None,
+            path: None
+        }
+    }
+}
+impl OutboxSettings
+{
+    ///Очередь хранящаяся в файле __path__
+    pub fn persistent(path: impl Into<PathBuf>) -> Self
+    {
+        Self
+        {
+            path: Some(path.into()),
+            ..Default::default()
+        }
+    }
+}
+
+struct Entry
+{
+    ///время (мс от unix epoch) после которого сообщение устаревает
+    expires: Option<u64>,
+    payload: Vec<u8>
+}
+impl Entry
+{
+    fn is_expired(&self, now: u64) -> bool
+    {
+        self.expires.is_some_and(|e| e <= now)
+    }
+}
+
+///Очередь исходящих сообщений клиента
+/// в файле хранятся записи: время устаревания (u64 be, 0 - не устаревает), длина (u32 be), сообщение;
+/// новые записи дописываются в конец файла, после отправки очереди файл переписывается оставшимися сообщениями
+pub(crate) struct Outbox
+{
+    settings: OutboxSettings,
+    queue: VecDeque<Entry>,
+    file: Option<File>,
+    ///количество записей в файле, включая вытесненные из очереди
+    records: usize
+}
+impl Outbox
+{
+    ///Открытие очереди, если задан файл то из него загружаются сохраненные сообщения
+    pub async fn open(settings: OutboxSettings) -> Self
+    {
+        let mut outbox = Self
+        {
+            settings,
+            queue: VecDeque::new(),
+            file: None,
+            records: 0
+        };
+        if let Some(path) = outbox.settings.path.clone()
+        {
+            match outbox.load(&path).await
+            {
+                Ok(file) => outbox.file = Some(file),
+                Err(e) => error!("Ошибка открытия файла очереди сообщений {} -> {}", path.display(), e)
+            }
+        }
+        outbox
+    }
+    async fn load(&mut self, path: &PathBuf) -> std::io::Result<File>
+    {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty())
+        {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let data = match tokio::fs::read(path).await
+        {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e)
+        };
+        let now = now();
+        for entry in decode(&data).into_iter().filter(|e| !e.is_expired(now))
+        {
+            self.enqueue(entry);
+        }
+        //файл переписывается без устаревших и вытесненных сообщений
+        self.rewrite(path).await
+    }
+    pub fn len(&self) -> usize
+    {
+        self.queue.len()
+    }
+    fn enqueue(&mut self, entry: Entry)
+    {
+        if self.queue.len() >= self.settings.capacity.max(1)
+        {
+            self.queue.pop_front();
+            warn!("Очередь сообщений переполнена, самое старое сообщение удалено");
+        }
+        self.queue.push_back(entry);
+    }
+    ///Добавление сообщения в очередь
+    pub async fn push(&mut self, payload: Vec<u8>)
+    {
+        let expires = self.settings.ttl.map(|ttl| now() + ttl.as_millis() as u64);
+        let entry = Entry { expires, payload };
+        let record = encode(&entry);
+        self.enqueue(entry);
+        if let Some(mut file) = self.file.take()
+        {
+            self.records += 1;
+            let result = if self.records > self.settings.capacity.max(1) * 2
+            {
+                self.save().await
+            }
+            else
+            {
+                file.write_all(&record).await.map(|_| file)
+            };
+            self.saved(result);
+        }
+    }
+    ///Отправка всех неустаревших сообщений очереди в порядке добавления, возвращает количество отправленных
+    /// если отправить сообщение не удалось то оно и все следующие остаются в очереди
+    pub async fn flush(&mut self, sender: &Outbound) -> usize
+    {
+        let now = now();
+        let mut sent = 0;
+        //сообщение удаляется из очереди только после того как оно принято соединением
+        while let Some(entry) = self.queue.front()
+        {
+            if !entry.is_expired(now)
+            {
+                if let Err(e) = sender.send(entry.payload.clone())
+                {
+                    error!("Ошибка отправки сообщения из очереди {}", e);
+                    break;
+                }
+                sent += 1;
+            }
+            self.queue.pop_front();
+        }
+        if self.file.take().is_some()
+        {
+            let result = self.save().await;
+            self.saved(result);
+        }
+        sent
+    }
+    pub fn is_empty(&self) -> bool
+    {
+        self.queue.is_empty()
+    }
+    async fn save(&mut self) -> std::io::Result<File>
+    {
+        let path = self.settings.path.clone().expect("файл очереди задан");
+        self.rewrite(&path).await
+    }
+    fn saved(&mut self, result: std::io::Result<File>)
+    {
+        match result
+        {
+            Ok(file) => self.file = Some(file),
+            Err(e) => error!("Ошибка записи в файл очереди сообщений, очередь больше не сохраняется в файл -> {}", e)
+        }
+    }
+    ///Перезапись файла текущим содержимым очереди, очередь записывается во временный файл
+    /// который затем заменяет основной, поэтому при сбое во время записи файл не теряется,
+    /// возвращается файл открытый для дописывания новых записей
+    async fn rewrite(&mut self, path: &Path) -> std::io::Result<File>
+    {
+        let data: Vec<u8> = self.queue.iter().flat_map(encode).collect();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp).await?;
+        file.write_all(&data).await?;
+        file.sync_data().await?;
+        drop(file);
+        tokio::fs::rename(&tmp, path).await?;
+        self.records = self.queue.len();
+        OpenOptions::new().append(true).open(path).await
+    }
+}
+
+fn now() -> u64
+{
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn encode(entry: &Entry) -> Vec<u8>
+{
+    let mut record = Vec::with_capacity(12 + entry.payload.len());
+    record.extend_from_slice(&entry.expires.unwrap_or(0).to_be_bytes());
+    record.extend_from_slice(&(entry.payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&entry.payload);
+    record
+}
+
+///Чтение записей файла, недописанная последняя запись отбрасывается
+fn decode(mut data: &[u8]) -> Vec<Entry>
+{
+    let mut entries = Vec::new();
+    while data.len() >= 12
+    {
+        let expires = u64::from_be_bytes(data[..8].try_into().unwrap());
+        let len = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+        if data.len() < 12 + len
+        {
+            break;
+        }
+        entries.push(Entry
+        {
+            expires: (expires != 0).then_some(expires),
+            payload: data[12..12 + len].to_vec()
+        });
+        data = &data[12 + len..];
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::Duration;
+    use futures::StreamExt;
+    use crate::transport::Outbound;
+    use super::{Outbox, OutboxSettings};
+
+    #[tokio::test]
+    async fn test_outbox()
+    {
+        let path = std::env::temp_dir().join("websocket_service_test").join("outbox.bin");
+        let _ = std::fs::remove_file(&path);
+        let settings = OutboxSettings { capacity: 3, ..OutboxSettings::persistent(&path) };
+        let mut outbox = Outbox::open(settings.clone()).await;
+        for i in 0..5u8
+        {
+            outbox.push(vec![i]).await;
+        }
+        assert_eq!(outbox.len(), 3);
+        drop(outbox);
+        //после перезапуска очередь загружается из файла
+        let mut outbox = Outbox::open(settings.clone()).await;
+        assert_eq!(outbox.len(), 3);
+        let (sender, stream) = Outbound::new(None, None, None, |_| ());
+        assert_eq!(outbox.flush(&sender).await, 3);
+        drop(sender);
+        let sent: Vec<Vec<u8>> = stream.map(|f| f.message.into_data()).collect().await;
+        assert_eq!(sent, vec![vec![2], vec![3], vec![4]]);
+        assert_eq!(Outbox::open(settings.clone()).await.len(), 0);
+        //сообщения которые не удалось отправить остаются в очереди и в файле
+        let mut outbox = Outbox::open(settings.clone()).await;
+        outbox.push(vec![5]).await;
+        let (sender, stream) = Outbound::new(None, None, None, |_| ());
+        drop(stream);
+        assert_eq!(outbox.flush(&sender).await, 0);
+        assert_eq!(outbox.len(), 1);
+        drop(outbox);
+        assert_eq!(Outbox::open(settings.clone()).await.len(), 1);
+        let (sender, _stream) = Outbound::new(None, None, None, |_| ());
+        assert_eq!(Outbox::open(settings.clone()).await.flush(&sender).await, 1);
+        //устаревшие сообщения не отправляются
+        let mut outbox = Outbox::open(OutboxSettings { ttl: Some(Duration::from_millis(10)), ..settings }).await;
+        outbox.push(vec![1]).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let (sender, _stream) = Outbound::new(None, None, None, |_| ());
+        assert_eq!(outbox.flush(&sender).await, 0);
+    }
+}
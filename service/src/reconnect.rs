@@ -0,0 +1,113 @@
+use std::time::Duration;
+use rand::Rng;
+
+///Политика переподключения клиента
+/// задержка перед попыткой __n__ равна `initial_delay * multiplier^(n-1)`, но не больше `max_delay`,
+/// затем случайно уменьшается на долю до `jitter`, чтобы много клиентов не подключались к серверу одновременно
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy
+{
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    ///доля задержки (от 0.0 до 1.0) на которую она может быть случайно уменьшена
+    pub jitter: f64,
+    ///сколько попыток подключения подряд делать прежде чем остановить клиента, `None` - бесконечно
+    pub max_attempts: Option<u32>,
+    ///переподключаться ли если сервер корректно закрыл соединение
+    pub reconnect_on_close: bool
+}
+impl Default for ReconnectPolicy
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            initial_delay: Duration::from_millis(500),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            max_attempts: None,
+            reconnect_on_close: true
+        }
+    }
+}
+impl ReconnectPolicy
+{
+    ///Задержка перед попыткой переподключения с номером __attempt__ (начиная с 1)
+    pub fn delay(&self, attempt: u32) -> Duration
+    {
+        let exp = self.multiplier.max(1.0).powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let delay = self.initial_delay.as_secs_f64() * exp;
+        let delay = delay.min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let delay = if jitter > 0.0
+        {
+            delay * (1.0 - rand::thread_rng().gen_range(0.0..=jitter))
+        }
+        else
+        {
+            delay
+        };
+        Duration::from_secs_f64(delay)
+    }
+    ///Можно ли сделать попытку с номером __attempt__
+    pub fn allows(&self, attempt: u32) -> bool
+    {
+        !matches!(self.max_attempts, Some(max) if attempt > max)
+    }
+    ///Номер попытки после разрыва соединения которое проработало __uptime__,
+    /// счетчик сбрасывается только если соединение продержалось не меньше `initial_delay`,
+    /// иначе сервер который принимает подключение и сразу его разрывает получал бы подключения без задержки
+    #[cfg(feature = "client")]
+    pub(crate) fn after_disconnect(&self, attempt: u32, uptime: Duration) -> u32
+    {
+        if uptime >= self.initial_delay
+        {
+            1
+        }
+        else
+        {
+            attempt.saturating_add(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::Duration;
+    use super::ReconnectPolicy;
+
+    #[test]
+    fn test_backoff()
+    {
+        let policy = ReconnectPolicy
+        {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.0,
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(10), Duration::from_secs(10));
+        assert!(policy.allows(3) && !policy.allows(4));
+        let policy = ReconnectPolicy { jitter: 0.5, ..policy };
+        for _ in 0..100
+        {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+        }
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_after_disconnect()
+    {
+        let policy = ReconnectPolicy { initial_delay: Duration::from_secs(1), ..Default::default() };
+        assert_eq!(policy.after_disconnect(2, Duration::from_secs(1)), 1);
+        assert_eq!(policy.after_disconnect(2, Duration::from_millis(10)), 3);
+    }
+}
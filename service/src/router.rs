@@ -0,0 +1,119 @@
+use std::{future::Future, sync::Arc};
+use futures::future::BoxFuture;
+use tokio_tungstenite::WebSocketStream;
+use crate::{server::{self, ConnectionInfo}, transport::Io, ServerConfig};
+
+///Подпись исходящих и проверка входящих фреймов соединения согласованные при рукопожатии,
+/// без фичи `seal` фреймы передаются как есть
+#[cfg(feature = "seal")]
+pub(crate) type Seal = Option<(crate::seal::Sealer, crate::seal::Opener)>;
+#[cfg(not(feature = "seal"))]
+pub(crate) type Seal = ();
+
+///Обработчик подключений точки подключения, получает соединение после рукопожатия
+pub(crate) type Serve = Arc<dyn Fn(WebSocketStream<Box<dyn Io>>, ConnectionInfo, Seal) -> BoxFuture<'static, ()> + Send + Sync>;
+
+///Точка подключения к серверу, создается через `Server::endpoint`
+pub struct Endpoint
+{
+    path: String,
+    serve: Box<dyn FnOnce(ServerConfig) -> Serve + Send>
+}
+impl Endpoint
+{
+    pub(crate) fn new<F>(path: &str, serve: F) -> Self where F: FnOnce(ServerConfig) -> Serve + Send + 'static
+    {
+        Self
+        {
+            path: normalize(path),
+            serve: Box::new(serve)
+        }
+    }
+}
+
+///Путь точки подключения всегда начинается с `/`
+pub(crate) fn normalize(path: &str) -> String
+{
+    if path.starts_with('/') { path.to_owned() } else { format!("/{}", path) }
+}
+
+///Точка подключения запущенного сервера, без пути принимает подключения с любым путем запроса
+pub(crate) struct Route
+{
+    path: Option<String>,
+    pub serve: Serve
+}
+impl Route
+{
+    pub fn any(serve: Serve) -> Self
+    {
+        Self
+        {
+            path: None,
+            serve
+        }
+    }
+    pub fn matches(&self, path: &str) -> bool
+    {
+        self.path.as_deref().is_none_or(|p| p == path)
+    }
+}
+
+///Несколько серверов с разными типами сообщений на одном адресе, сервер выбирается по пути запроса:
+/// ```ignore
+/// Router::new()
+///     .route(ChatServer::endpoint("/chat", on_chat))
+///     .route(TelemetryServer::endpoint("/telemetry", on_telemetry))
+///     .start("127.0.0.1:3010", ServerConfig::default())
+///     .await;
+/// ```
+/// подключения с неизвестным путем отклоняются при рукопожатии с кодом 404
+#[derive(Default)]
+pub struct Router
+{
+    endpoints: Vec<Endpoint>
+}
+impl Router
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+    ///Добавление точки подключения, если путь уже занят то подключения получает первая добавленная точка
+    pub fn route(mut self, endpoint: Endpoint) -> Self
+    {
+        self.endpoints.push(endpoint);
+        self
+    }
+    ///Запуск приема подключений на адресе __host__, настройки __config__ общие для всех точек подключения
+    pub fn start(self, host: &str, config: ServerConfig) -> impl Future<Output = ()> + Send
+    {
+        let addr = host.to_string();
+        async move
+        {
+            let routes = self.endpoints.into_iter().map(|e| Route
+            {
+                path: Some(e.path),
+                serve: (e.serve)(config.clone())
+            }).collect();
+            server::listen(addr, config, routes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::sync::Arc;
+    use super::Route;
+
+    #[test]
+    fn test_matches()
+    {
+        let route = Route { path: Some("/chat".to_owned()), serve: Arc::new(|_, _, _| Box::pin(async {})) };
+        assert!(route.matches("/chat"));
+        assert!(!route.matches("/chat/1"));
+        assert!(!route.matches("/"));
+        assert!(Route::any(route.serve.clone()).matches("/telemetry"));
+    }
+}
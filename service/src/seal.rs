@@ -0,0 +1,571 @@
+use std::{fmt::{Debug, Display}, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use chacha20poly1305::{aead::{Aead, Payload}, KeyInit, XChaCha20Poly1305, XNonce};
+use dashmap::{mapref::entry::Entry, DashMap};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use tokio_tungstenite::tungstenite::{http::{HeaderMap, HeaderValue}, Message};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+///Ошибка настройки ключей, обмена ключами или проверки фрейма
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SealError
+{
+    ///открытый ключ Ed25519 не является точкой кривой
+    InvalidKey,
+    ///фрейм не подписан, обрезан или отправлен не той стороной соединения
+    Malformed,
+    ///подпись не совпала: фрейм изменен или подписан другим ключом
+    BadSignature,
+    ///фрейм уже был принят или пришел не по порядку
+    Replayed,
+    ///время отправки первого фрейма соединения отличается от текущего больше чем на `Sealing::max_age`
+    Expired,
+    ///не удалось расшифровать фрейм
+    Decryption,
+    ///вторая сторона не прислала открытый ключ при рукопожатии или ключ не прошел проверку подписи
+    KeyExchange(String)
+}
+impl Display for SealError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            SealError::InvalidKey => f.write_str("Недопустимый открытый ключ Ed25519"),
+            SealError::Malformed => f.write_str("Фрейм не подписан или поврежден"),
+            SealError::BadSignature => f.write_str("Подпись фрейма не совпала"),
+            SealError::Replayed => f.write_str("Повторный фрейм или нарушен порядок фреймов"),
+            SealError::Expired => f.write_str("Фрейм отправлен слишком давно"),
+            SealError::Decryption => f.write_str("Ошибка расшифровки фрейма"),
+            SealError::KeyExchange(e) => write!(f, "Ошибка обмена ключами: {}", e)
+        }
+    }
+}
+impl std::error::Error for SealError {}
+
+///Ключ шифрования фреймов
+#[derive(Clone, PartialEq, Eq)]
+pub enum Encryption
+{
+    ///общий ключ заданный заранее на обеих сторонах
+    PreShared([u8; 32]),
+    ///для каждого соединения стороны обмениваются эфемерными ключами X25519 в заголовках рукопожатия,
+    ///открытые ключи подписываются ключом подписи, поэтому подменить их посредник не может
+    Exchange
+}
+impl Debug for Encryption
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Encryption::PreShared(_) => f.write_str("PreShared(..)"),
+            Encryption::Exchange => f.write_str("Exchange")
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Signing
+{
+    Hmac(Arc<[u8]>),
+    Ed25519
+    {
+        key: Arc<SigningKey>,
+        peers: Arc<[VerifyingKey]>
+    }
+}
+impl Signing
+{
+    fn len(&self) -> usize
+    {
+        match self
+        {
+            Signing::Hmac(_) => 32,
+            Signing::Ed25519 { .. } => 64
+        }
+    }
+    fn sign(&self, data: &[u8]) -> Vec<u8>
+    {
+        match self
+        {
+            Signing::Hmac(key) => hmac(key, data).finalize().into_bytes().to_vec(),
+            Signing::Ed25519 { key, .. } => key.sign(data).to_vec()
+        }
+    }
+    ///Проверка подписи, возвращает номер открытого ключа второй стороны которым подписаны данные,
+    /// если __peer__ задан то проверяется только этот ключ
+    fn verify(&self, data: &[u8], signature: &[u8], peer: Option<usize>) -> Result<usize, SealError>
+    {
+        match self
+        {
+            Signing::Hmac(key) => hmac(key, data).verify_slice(signature).map(|_| 0).map_err(|_| SealError::BadSignature),
+            Signing::Ed25519 { peers, .. } =>
+            {
+                let signature = Signature::from_slice(signature).map_err(|_| SealError::BadSignature)?;
+                peers.iter()
+                    .enumerate()
+                    .filter(|(i, _)| peer.is_none_or(|p| p == *i))
+                    .find(|(_, key)| key.verify(data, &signature).is_ok())
+                    .map(|(i, _)| i)
+                    .ok_or(SealError::BadSignature)
+            }
+        }
+    }
+}
+fn hmac(key: &[u8], data: &[u8]) -> Hmac<Sha256>
+{
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC принимает ключ любой длины");
+    mac.update(data);
+    mac
+}
+
+///Подпись и шифрование фреймов сообщений:
+/// ```ignore
+/// let sealing = Sealing::hmac(b"secret").encrypt(Encryption::Exchange);
+/// let config = ServerConfig { sealing: Some(sealing), ..Default::default() };
+/// ```
+/// каждый фрейм с данными подписывается (HMAC-SHA256 или Ed25519) и при необходимости шифруется XChaCha20-Poly1305,
+/// фрейм содержит номер по порядку и время отправки, поэтому повторно отправленный посредником фрейм отклоняется
+/// служебные фреймы websocket (ping, pong, close) не подписываются
+///
+/// фрейм который не прошел проверку отбрасывается до вызова обработчика и соединение закрывается
+#[derive(Clone)]
+pub struct Sealing
+{
+    signing: Signing,
+    encryption: Option<Encryption>,
+    max_age: Duration
+}
+impl Debug for Sealing
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        let signing = match &self.signing
+        {
+            Signing::Hmac(_) => "HMAC-SHA256",
+            Signing::Ed25519 { .. } => "Ed25519"
+        };
+        f.debug_struct("Sealing").field("signing", &signing).field("encryption", &self.encryption).field("max_age", &self.max_age).finish()
+    }
+}
+impl Sealing
+{
+    ///Подпись HMAC-SHA256 общим ключом __key__
+    pub fn hmac(key: impl AsRef<[u8]>) -> Self
+    {
+        Self::new(Signing::Hmac(key.as_ref().into()))
+    }
+    ///Подпись Ed25519 своим секретным ключом __secret__, входящие фреймы должны быть подписаны одним из ключей __peers__,
+    /// за время соединения вторая сторона не может сменить ключ
+    pub fn ed25519<I>(secret: &[u8; 32], peers: I) -> Result<Self, SealError> where I: IntoIterator<Item = [u8; 32]>
+    {
+        let peers = peers.into_iter()
+            .map(|p| VerifyingKey::from_bytes(&p).map_err(|_| SealError::InvalidKey))
+            .collect::<Result<Arc<[_]>, _>>()?;
+        Ok(Self::new(Signing::Ed25519 { key: Arc::new(SigningKey::from_bytes(secret)), peers }))
+    }
+    ///Открытый ключ Ed25519 для секретного ключа __secret__, его передают второй стороне
+    pub fn ed25519_public_key(secret: &[u8; 32]) -> [u8; 32]
+    {
+        SigningKey::from_bytes(secret).verifying_key().to_bytes()
+    }
+    fn new(signing: Signing) -> Self
+    {
+        Self
+        {
+            signing,
+            encryption: None,
+            max_age: Duration::from_secs(30)
+        }
+    }
+    pub fn encrypt(mut self, encryption: Encryption) -> Self
+    {
+        self.encryption = Some(encryption);
+        self
+    }
+    ///Допустимая разница между временем отправки первого фрейма соединения и временем его получения
+    /// с учетом расхождения часов, по умолчанию 30 секунд; следующие фреймы проверяются только по номеру,
+    /// поэтому фреймы ожидающие медленного обработчика не отклоняются.
+    /// Номера принятых потоков хранятся в памяти процесса: если за балансировщиком несколько экземпляров сервера
+    /// с одним ключом, перехваченный первый фрейм соединения может быть принят другим экземпляром в течение `max_age`
+    pub fn max_age(mut self, max_age: Duration) -> Self
+    {
+        self.max_age = max_age;
+        self
+    }
+    ///Начало рукопожатия клиента: заголовки запроса с открытым ключом если ключами надо обменяться
+    #[cfg(feature = "client")]
+    pub(crate) fn offer(&self) -> (Offer, HeaderMap)
+    {
+        let mut headers = HeaderMap::new();
+        if self.encryption != Some(Encryption::Exchange)
+        {
+            return (Offer(None), headers);
+        }
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        headers.insert(KEY_HEADER, self.key_header(&PublicKey::from(&secret), Role::Client));
+        (Offer(Some(secret)), headers)
+    }
+    ///Рукопожатие на сервере: проверка открытого ключа клиента из __request__ и ответ своим открытым ключом в __response__
+    #[cfg(feature = "server")]
+    pub(crate) fn accept(&self, request: &HeaderMap, response: &mut HeaderMap) -> Result<(Sealer, Opener), SealError>
+    {
+        let (key, peer) = match self.encryption.as_ref()
+        {
+            None => (None, None),
+            Some(Encryption::PreShared(key)) => (Some(*key), None),
+            Some(Encryption::Exchange) =>
+            {
+                let (client, peer) = self.peer_key(request, Role::Client)?;
+                let secret = EphemeralSecret::random_from_rng(OsRng);
+                let public = PublicKey::from(&secret);
+                response.insert(KEY_HEADER, self.key_header(&public, Role::Server));
+                (Some(derive(secret, &client, &client, &public)?), Some(peer))
+            }
+        };
+        Ok(self.channel(Role::Server, key, peer))
+    }
+    ///Открытый ключ X25519 __public__ подписанный ключом подписи стороны __role__ для заголовка рукопожатия
+    fn key_header(&self, public: &PublicKey, role: Role) -> HeaderValue
+    {
+        let signature = self.signing.sign(&exchange_data(public, role));
+        let value = format!("{}.{}", hex(public.as_bytes()), hex(&signature));
+        HeaderValue::from_str(&value).expect("hex допустим в заголовке")
+    }
+    ///Открытый ключ X25519 второй стороны __role__ из заголовков __headers__ и номер ключа подписи которым он подписан
+    fn peer_key(&self, headers: &HeaderMap, role: Role) -> Result<(PublicKey, usize), SealError>
+    {
+        let value = headers.get(KEY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| SealError::KeyExchange(format!("нет заголовка {}", KEY_HEADER)))?;
+        let invalid = || SealError::KeyExchange(format!("недопустимое значение заголовка {}", KEY_HEADER));
+        let (public, signature) = value.split_once('.').ok_or_else(invalid)?;
+        let public: [u8; 32] = unhex(public).and_then(|p| p.try_into().ok()).ok_or_else(invalid)?;
+        let signature = unhex(signature).ok_or_else(invalid)?;
+        let public = PublicKey::from(public);
+        let peer = self.signing.verify(&exchange_data(&public, role), &signature, None)
+            .map_err(|_| SealError::KeyExchange("подпись открытого ключа не совпала".to_owned()))?;
+        Ok((public, peer))
+    }
+    fn channel(&self, role: Role, key: Option<[u8; 32]>, peer: Option<usize>) -> (Sealer, Opener)
+    {
+        let cipher = key.map(|k| XChaCha20Poly1305::new(&k.into()));
+        let encrypted = if cipher.is_some() { ENCRYPTED } else { 0 };
+        let flags = |from: Role| encrypted | if from == Role::Client { FROM_CLIENT } else { 0 };
+        let sealer = Sealer
+        {
+            signing: self.signing.clone(),
+            cipher: cipher.clone(),
+            flags: flags(role),
+            stream: rand::random(),
+            counter: 0
+        };
+        let opener = Opener
+        {
+            signing: self.signing.clone(),
+            cipher,
+            flags: flags(role.peer()),
+            max_age: self.max_age,
+            peer,
+            stream: None,
+            counter: 0
+        };
+        (sealer, opener)
+    }
+}
+
+///Заголовок рукопожатия с открытым ключом X25519 и его подписью, в hex через точку
+const KEY_HEADER: &str = "x-seal-key";
+///Маркер подписанного фрейма, формат: маркер, флаги, номер потока (u64 be), номер фрейма (u64 be),
+/// время отправки в мс (u64 be), данные (зашифрованные если задано шифрование), подпись всего предыдущего
+const MARKER: u8 = 0x5E;
+const HEADER_LEN: usize = 26;
+const ENCRYPTED: u8 = 0b01;
+const FROM_CLIENT: u8 = 0b10;
+
+///Сторона соединения, фрейм подписанный одной стороной не принимается от другой
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role
+{
+    Client,
+    Server
+}
+impl Role
+{
+    fn peer(self) -> Self
+    {
+        match self
+        {
+            Role::Client => Role::Server,
+            Role::Server => Role::Client
+        }
+    }
+}
+
+fn exchange_data(public: &PublicKey, role: Role) -> Vec<u8>
+{
+    let mut data = b"seal-exchange".to_vec();
+    data.push(role as u8);
+    data.extend_from_slice(public.as_bytes());
+    data
+}
+///Ключ шифрования соединения из общего секрета X25519 и открытых ключей клиента и сервера
+fn derive(secret: EphemeralSecret, peer: &PublicKey, client: &PublicKey, server: &PublicKey) -> Result<[u8; 32], SealError>
+{
+    let shared = secret.diffie_hellman(peer);
+    if !shared.was_contributory()
+    {
+        return Err(SealError::KeyExchange("недопустимый открытый ключ X25519".to_owned()));
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(b"seal-key");
+    hasher.update(shared.as_bytes());
+    hasher.update(client.as_bytes());
+    hasher.update(server.as_bytes());
+    Ok(hasher.finalize().into())
+}
+fn hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+fn unhex(value: &str) -> Option<Vec<u8>>
+{
+    if !value.len().is_multiple_of(2)
+    {
+        return None;
+    }
+    (0..value.len()).step_by(2).map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok()).collect()
+}
+fn now() -> u64
+{
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+///Эфемерный ключ клиента ожидающий ответа сервера
+#[cfg(feature = "client")]
+pub(crate) struct Offer(Option<EphemeralSecret>);
+#[cfg(feature = "client")]
+impl Offer
+{
+    ///Завершение рукопожатия клиента по заголовкам ответа сервера __response__
+    pub fn finish(self, sealing: &Sealing, response: &HeaderMap) -> Result<(Sealer, Opener), SealError>
+    {
+        let (key, peer) = match (self.0, sealing.encryption.as_ref())
+        {
+            (Some(secret), _) =>
+            {
+                let public = PublicKey::from(&secret);
+                let (server, peer) = sealing.peer_key(response, Role::Server)?;
+                (Some(derive(secret, &server, &public, &server)?), Some(peer))
+            },
+            (None, Some(Encryption::PreShared(key))) => (Some(*key), None),
+            _ => (None, None)
+        };
+        Ok(sealing.channel(Role::Client, key, peer))
+    }
+}
+
+///Подпись и шифрование исходящих фреймов соединения
+pub(crate) struct Sealer
+{
+    signing: Signing,
+    cipher: Option<XChaCha20Poly1305>,
+    flags: u8,
+    ///случайный номер потока фреймов этого соединения
+    stream: u64,
+    counter: u64
+}
+impl Sealer
+{
+    ///Подписанный фрейм для фрейма с данными, служебные фреймы не изменяются
+    pub fn seal(&mut self, message: Message) -> Message
+    {
+        let data = match message
+        {
+            Message::Binary(data) => data,
+            Message::Text(text) => text.into_bytes(),
+            message => return message
+        };
+        self.counter += 1;
+        let mut frame = Vec::with_capacity(HEADER_LEN + data.len() + self.signing.len() + 16);
+        frame.push(MARKER);
+        frame.push(self.flags);
+        frame.extend_from_slice(&self.stream.to_be_bytes());
+        frame.extend_from_slice(&self.counter.to_be_bytes());
+        frame.extend_from_slice(&now().to_be_bytes());
+        match self.cipher.as_ref()
+        {
+            //номер потока, номер фрейма и время вместе уникальны и служат nonce
+            Some(cipher) => match cipher.encrypt(XNonce::from_slice(&frame[2..HEADER_LEN]), Payload { msg: &data, aad: &frame })
+            {
+                Ok(encrypted) => frame.extend_from_slice(&encrypted),
+                Err(e) => logger::error!("Ошибка шифрования фрейма: {}", e)
+            },
+            None => frame.extend_from_slice(&data)
+        }
+        let signature = self.signing.sign(&frame);
+        frame.extend_from_slice(&signature);
+        Message::Binary(frame)
+    }
+}
+
+///Номера потоков принятых за последние 2 * `max_age`: первый фрейм потока нельзя повторить в другом соединении,
+/// а более старый поток отклоняется по времени отправки
+static STREAMS: Lazy<DashMap<u64, Instant>> = Lazy::new(DashMap::new);
+
+///Регистрация нового потока __stream__, false если поток уже встречался
+fn first_seen(stream: u64, max_age: Duration) -> bool
+{
+    let now = Instant::now();
+    STREAMS.retain(|_, forget| *forget > now);
+    match STREAMS.entry(stream)
+    {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(entry) =>
+        {
+            entry.insert(now + max_age * 2);
+            true
+        }
+    }
+}
+
+///Проверка и расшифровка входящих фреймов соединения
+pub(crate) struct Opener
+{
+    signing: Signing,
+    cipher: Option<XChaCha20Poly1305>,
+    ///флаги которые должны быть у фрейма второй стороны
+    flags: u8,
+    max_age: Duration,
+    ///ключ второй стороны, после первого принятого фрейма или обмена ключами другие ключи не принимаются
+    peer: Option<usize>,
+    stream: Option<u64>,
+    counter: u64
+}
+impl Opener
+{
+    ///Данные подписанного фрейма, фреймы должны приходить строго по порядку начиная с первого
+    pub fn open(&mut self, data: &[u8]) -> Result<Vec<u8>, SealError>
+    {
+        if data.len() < HEADER_LEN + self.signing.len() || data[0] != MARKER
+        {
+            return Err(SealError::Malformed);
+        }
+        let (signed, signature) = data.split_at(data.len() - self.signing.len());
+        let peer = self.signing.verify(signed, signature, self.peer)?;
+        if signed[1] != self.flags
+        {
+            return Err(SealError::Malformed);
+        }
+        let number = |i: usize| u64::from_be_bytes(signed[i..i + 8].try_into().unwrap());
+        let (stream, counter, sent) = (number(2), number(10), number(18));
+        //порядок следующих фреймов гарантирует номер, время проверяется только у первого
+        if self.stream.is_none() && now().abs_diff(sent) > self.max_age.as_millis() as u64
+        {
+            return Err(SealError::Expired);
+        }
+        let next = match self.stream
+        {
+            Some(current) => current == stream && counter == self.counter + 1,
+            None => counter == 1
+        };
+        if !next
+        {
+            return Err(SealError::Replayed);
+        }
+        let (header, body) = signed.split_at(HEADER_LEN);
+        let payload = match self.cipher.as_ref()
+        {
+            Some(cipher) => cipher.decrypt(XNonce::from_slice(&header[2..]), Payload { msg: body, aad: header }).map_err(|_| SealError::Decryption)?,
+            None => body.to_vec()
+        };
+        if self.stream.is_none() && !first_seen(stream, self.max_age)
+        {
+            return Err(SealError::Replayed);
+        }
+        self.peer = Some(peer);
+        self.stream = Some(stream);
+        self.counter = counter;
+        Ok(payload)
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod tests
+{
+    use tokio_tungstenite::tungstenite::{http::HeaderMap, Message};
+    use super::{Encryption, SealError, Sealing, HEADER_LEN};
+
+    #[test]
+    fn test_seal()
+    {
+        let sealing = Sealing::hmac(b"secret");
+        let (_, mut opener) = sealing.accept(&HeaderMap::new(), &mut HeaderMap::new()).unwrap();
+        let (offer, _) = sealing.offer();
+        let (mut sealer, _) = offer.finish(&sealing, &HeaderMap::new()).unwrap();
+        let first = sealer.seal(Message::Binary(b"first".to_vec())).into_data();
+        let second = sealer.seal(Message::Text("second".to_owned())).into_data();
+        assert_eq!(sealer.seal(Message::Ping(vec![1])), Message::Ping(vec![1]));
+        //фрейм не по порядку и измененный фрейм отклоняются
+        assert_eq!(opener.open(&second), Err(SealError::Replayed));
+        let mut tampered = first.clone();
+        tampered[HEADER_LEN] ^= 1;
+        assert_eq!(opener.open(&tampered), Err(SealError::BadSignature));
+        assert_eq!(opener.open(b"first"), Err(SealError::Malformed));
+        assert_eq!(opener.open(&first).unwrap(), b"first");
+        assert_eq!(opener.open(&second).unwrap(), b"second");
+        assert_eq!(opener.open(&second), Err(SealError::Replayed));
+        //поток уже принятый одним соединением не принимается другим
+        let (_, mut other) = sealing.accept(&HeaderMap::new(), &mut HeaderMap::new()).unwrap();
+        assert_eq!(other.open(&first), Err(SealError::Replayed));
+        //фрейм сервера нельзя вернуть серверу
+        let (mut server, _) = sealing.accept(&HeaderMap::new(), &mut HeaderMap::new()).unwrap();
+        assert_eq!(other.open(&server.seal(Message::Binary(vec![1])).into_data()), Err(SealError::Malformed));
+        //время отправки проверяется только у первого фрейма соединения
+        let sealing = sealing.max_age(std::time::Duration::from_millis(50));
+        let (_, mut opener) = sealing.accept(&HeaderMap::new(), &mut HeaderMap::new()).unwrap();
+        let (mut sealer, _) = sealing.offer().0.finish(&sealing, &HeaderMap::new()).unwrap();
+        let stale = sealer.seal(Message::Binary(vec![1])).into_data();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(opener.open(&stale), Err(SealError::Expired));
+        let (mut sealer, _) = sealing.offer().0.finish(&sealing, &HeaderMap::new()).unwrap();
+        let first = sealer.seal(Message::Binary(vec![1])).into_data();
+        let second = sealer.seal(Message::Binary(vec![2])).into_data();
+        assert_eq!(opener.open(&first).unwrap(), vec![1]);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(opener.open(&second).unwrap(), vec![2]);
+        //другой ключ
+        let (mut sealer, _) = Sealing::hmac(b"other").offer().0.finish(&Sealing::hmac(b"other"), &HeaderMap::new()).unwrap();
+        assert_eq!(other.open(&sealer.seal(Message::Binary(vec![1])).into_data()), Err(SealError::BadSignature));
+    }
+
+    #[test]
+    fn test_encryption()
+    {
+        let client_secret = [1; 32];
+        let server_secret = [2; 32];
+        let client = Sealing::ed25519(&client_secret, [Sealing::ed25519_public_key(&server_secret)]).unwrap().encrypt(Encryption::Exchange);
+        let server = Sealing::ed25519(&server_secret, [Sealing::ed25519_public_key(&client_secret)]).unwrap().encrypt(Encryption::Exchange);
+        let (offer, request) = client.offer();
+        let mut response = HeaderMap::new();
+        let (mut server_sealer, mut server_opener) = server.accept(&request, &mut response).unwrap();
+        let (mut client_sealer, mut client_opener) = offer.finish(&client, &response).unwrap();
+        let frame = client_sealer.seal(Message::Binary(b"secret payload".to_vec())).into_data();
+        assert!(!frame.windows(6).any(|w| w == b"secret"));
+        assert_eq!(server_opener.open(&frame).unwrap(), b"secret payload");
+        assert_eq!(client_opener.open(&server_sealer.seal(Message::Binary(b"reply".to_vec())).into_data()).unwrap(), b"reply");
+        //без подписанного открытого ключа клиента рукопожатие отклоняется
+        assert!(matches!(server.accept(&HeaderMap::new(), &mut HeaderMap::new()), Err(SealError::KeyExchange(_))));
+        let stranger = Sealing::ed25519(&[3; 32], [Sealing::ed25519_public_key(&server_secret)]).unwrap().encrypt(Encryption::Exchange);
+        assert!(matches!(server.accept(&stranger.offer().1, &mut HeaderMap::new()), Err(SealError::KeyExchange(_))));
+        //общий ключ шифрования, у сторон с разными ключами фрейм не расшифровывается
+        let sealing = Sealing::hmac(b"secret").encrypt(Encryption::PreShared([7; 32]));
+        let (mut sealer, _) = sealing.offer().0.finish(&sealing, &HeaderMap::new()).unwrap();
+        let (_, mut opener) = sealing.clone().encrypt(Encryption::PreShared([8; 32])).accept(&HeaderMap::new(), &mut HeaderMap::new()).unwrap();
+        assert_eq!(opener.open(&sealer.seal(Message::Binary(vec![1])).into_data()), Err(SealError::Decryption));
+    }
+}
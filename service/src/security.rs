@@ -0,0 +1,75 @@
+use std::net::SocketAddr;
+use futures::{stream::{self, BoxStream}, StreamExt};
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use crate::LimitAction;
+
+///Событие безопасности сервера: подключение или сообщение отклонено по правилам сервера
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecurityEvent
+{
+    ///источник (заголовок `Origin`) подключения не входит в `ServerConfig::origins`, клиенту возвращен ответ 403
+    OriginRejected
+    {
+        peer: SocketAddr,
+        origin: Option<String>
+    },
+    ///клиент превысил `RateLimits` сервера, событие возникает один раз пока скорость клиента не вернется в норму
+    RateLimited
+    {
+        peer: SocketAddr,
+        action: LimitAction
+    },
+    ///с ip адреса клиента уже открыто `RateLimits::max_connections_per_ip` подключений, новое подключение закрыто
+    TooManyConnections
+    {
+        peer: SocketAddr
+    },
+    ///токен клиента не прошел проверку при подключении или при обновлении,
+    ///либо открытый ключ клиента для шифрования фреймов не прошел проверку подписи
+    AuthFailed
+    {
+        peer: SocketAddr,
+        reason: String
+    },
+    ///срок действия токена клиента истек и клиент не прислал новый, соединение закрыто
+    TokenExpired
+    {
+        peer: SocketAddr
+    },
+    ///фрейм клиента не прошел проверку подписи, расшифровку или был отправлен повторно, соединение закрыто
+    FrameRejected
+    {
+        peer: SocketAddr,
+        reason: String
+    }
+}
+
+///Сколько событий хранится для подписчиков которые не успевают их читать
+const EVENTS_CAPACITY: usize = 256;
+static EVENTS: Lazy<broadcast::Sender<SecurityEvent>> = Lazy::new(|| broadcast::Sender::new(EVENTS_CAPACITY));
+
+///Регистрация события, событие пишется в лог и передается подписчикам `security_events`
+pub(crate) fn emit(event: SecurityEvent)
+{
+    logger::warn!("Событие безопасности: {:?}", &event);
+    let _ = EVENTS.send(event);
+}
+
+///Поток событий безопасности всех серверов, начиная с момента подписки,
+/// если подписчик отстал больше чем на 256 событий то старые события пропускаются
+pub fn security_events() -> BoxStream<'static, SecurityEvent>
+{
+    stream::unfold(EVENTS.subscribe(), |mut events| async move
+    {
+        loop
+        {
+            match events.recv().await
+            {
+                Ok(event) => return Some((event, events)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None
+            }
+        }
+    }).boxed()
+}
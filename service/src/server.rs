@@ -1,90 +1,235 @@
 use anyhow::Context;
 use logger::{debug, error};
 use once_cell::sync::Lazy;
-use tokio::sync::RwLock;
-use tokio_tungstenite::tungstenite::Message;
-use std::{collections::HashMap, sync::Arc};
-use std::net::SocketAddr;
-use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use dashmap::DashMap;
+use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use futures_util::pin_mut;
-use futures::{future, stream::StreamExt, TryStreamExt};
+use futures::{future::{self, Either}, sink, stream::{BoxStream, StreamExt}, FutureExt, Sink, TryStreamExt};
+use futures_channel::mpsc::{unbounded, UnboundedSender};
+use tokio_tungstenite::{tungstenite::{handshake::server::{ErrorResponse, Request, Response}, http::{header, StatusCode}}, WebSocketStream};
+#[cfg(feature = "tls")]
+use crate::tls;
+#[cfg(unix)]
+use crate::unix;
+#[cfg(any(feature = "jwt", feature = "seal"))]
+use tokio_tungstenite::tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame}, Message};
+#[cfg(feature = "jwt")]
+use crate::{auth::AuthFrame, jwt, AuthError};
+#[cfg(feature = "seal")]
+use crate::SealError;
+use crate::{auth, files::{self, Inbox}, heartbeat::Heartbeat, limits::{Limiter, Violation}, router::{self, Endpoint, Route, Seal, Serve}, security::{self, SecurityEvent}, transport::{self, Inbound, Io, Outbound, Outgoing, Writer}, BatchSettings, ChunkProgress, ChunkSettings, FileSettings, LimitAction, ReceivedFile, SendError, ServerConfig, TransferError};
 
-///Список подключенных клиентов с каналом для оправки им сообщений
-static CLIENTS: Lazy<Arc<RwLock<HashMap<SocketAddr, UnboundedSender<Message>>>>> = Lazy::new(|| 
+///Идентификатор подключения клиента к серверу - адрес клиента
+pub type ConnectionId = SocketAddr;
+
+///Сведения о подключении клиента
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo
+{
+    pub id: ConnectionId,
+    ///путь запроса рукопожатия, например `/chat`
+    pub path: String,
+    ///субъект проверенного сертификата клиента (`CN=..., O=...`), если сервер требует сертификат клиента
+    pub client_subject: Option<String>,
+    ///claims проверенного JWT клиента, после обновления токена без переподключения claims нового токена
+    pub claims: Option<serde_json::Value>
+}
+
+///Подключенный клиент и сервер (реализация трейта `Server`) через который он подключен
+struct Connected
+{
+    scope: Scope,
+    sender: Outbound
+}
+///Сервер и точка подключения через которые подключен клиент, по ним разделяются списки клиентов разных серверов
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Scope
+{
+    ///имя реализации трейта `Server`
+    server: &'static str,
+    ///имя типа сообщений, одна реализация может обслуживать несколько типов сообщений
+    message: &'static str,
+    ///путь точки подключения, `None` если сервер запущен без `Router`
+    path: Option<String>
+}
+impl Scope
+{
+    fn new<S: ?Sized, T>(path: Option<String>) -> Self
+    {
+        Self
+        {
+            server: std::any::type_name::<S>(),
+            message: std::any::type_name::<T>(),
+            path
+        }
+    }
+    ///Точка подключения сервера __S__ с сообщениями __T__, обработчик которой сейчас выполняется,
+    /// вне обработчика точки подключения - сервер запущенный без `Router`
+    fn current<S: ?Sized, T>() -> Self
+    {
+        Self::new::<S, T>(ENDPOINT.try_with(|path| path.clone()).ok().flatten())
+    }
+    ///Клиент подключен через сервер __S__ с сообщениями __T__ через любую точку подключения
+    fn is<S: ?Sized, T>(&self) -> bool
+    {
+        self.server == std::any::type_name::<S>() && self.message == std::any::type_name::<T>()
+    }
+}
+///Список подключенных клиентов с каналом для оправки им сообщений  
+///разбит на шарды, поэтому подключение и отключение клиентов не блокирует весь список
+static CLIENTS: Lazy<DashMap<SocketAddr, Connected>> = Lazy::new(DashMap::new);
+///Сведения о подключенных клиентах
+static PEERS: Lazy<DashMap<ConnectionId, ConnectionInfo>> = Lazy::new(DashMap::new);
+tokio::task_local!
 {
-    Arc::new(RwLock::new(HashMap::new()))
-});
+    ///Путь точки подключения, обработчик сообщения которой сейчас выполняется
+    static ENDPOINT: Option<String>;
+}
+///Очереди принятых файлов, у каждого сервера и типа сообщений своя очередь общая для всех его точек подключения
+static INBOXES: Lazy<DashMap<Scope, Arc<Inbox>>> = Lazy::new(DashMap::new);
+///Очередь принятых файлов сервера и типа сообщений из __scope__
+fn inbox(scope: &Scope) -> Arc<Inbox>
+{
+    let key = Scope { path: None, ..scope.clone() };
+    INBOXES.entry(key).or_insert_with(|| Arc::new(Inbox::new())).clone()
+}
 
 pub trait Server<T> where T: 'static + serde::Serialize + Send + Sync, for <'de> T : serde::Deserialize<'de> + Sized + Send
 {
-    fn start_server<F, Fut: std::future::Future<Output = ()> + Send + Sync>(host: &str, f: F) -> impl std::future::Future<Output = ()> + Send
+    ///Настройки пакетной отправки сообщений, по умолчанию каждое сообщение отправляется отдельным фреймом
+    fn batching() -> Option<BatchSettings>
+    {
+        None
+    }
+    ///Настройки разбиения больших сообщений на фрагменты, по умолчанию сообщение отправляется одним фреймом
+    fn chunking() -> Option<ChunkSettings>
+    {
+        None
+    }
+    ///Прогресс отправки и получения сообщений разбитых на фрагменты
+    fn on_chunk_progress(_addr: SocketAddr, _progress: ChunkProgress)
+    {
+    }
+    ///Настройки передачи файлов, по умолчанию прием файлов от клиентов отключен
+    fn files() -> Option<FileSettings>
+    {
+        None
+    }
+    fn start_server<F, Fut: std::future::Future<Output = ()> + Send + Sync + 'static>(host: &str, f: F) -> impl std::future::Future<Output = ()> + Send
+    where F:  Send + Sync + 'static + Clone + Fn(SocketAddr, T) -> Fut
+    {
+        Self::start_server_with_config(host, ServerConfig::default(), f)
+    }
+    fn start_server_with_config<F, Fut: std::future::Future<Output = ()> + Send + Sync + 'static>(host: &str, config: ServerConfig, f: F) -> impl std::future::Future<Output = ()> + Send
     where F:  Send + Sync + 'static + Clone + Fn(SocketAddr, T) -> Fut
     {
         async move {
             let addr = host.to_string();
-            tokio::spawn(async move
+            let serve = serve(settings::<Self, T>(config.clone(), None, None), f);
+            listen(addr, config, vec![Route::any(serve)]);
+        }
+    }
+    ///Точка подключения __path__ для запуска нескольких серверов на одном адресе через `Router`,
+    /// у каждой точки подключения свой обработчик и свой список клиентов для рассылки сообщений
+    fn endpoint<F, Fut: std::future::Future<Output = ()> + Send + Sync + 'static>(path: &str, f: F) -> Endpoint
+    where F:  Send + Sync + 'static + Clone + Fn(SocketAddr, T) -> Fut
+    {
+        let scope = router::normalize(path);
+        Endpoint::new(path, move |config| serve(settings::<Self, T>(config, Some(scope), None), f))
+    }
+    ///Запуск сервера, входящие сообщения вместе с адресом отправителя читаются из возвращаемого потока вместо обработчика
+    fn start_server_stream(host: &str, config: ServerConfig) -> impl std::future::Future<Output = BoxStream<'static, (ConnectionId, T)>> + Send
+    {
+        let addr = host.to_string();
+        let (incoming, receiver) = unbounded::<(ConnectionId, Vec<u8>)>();
+        let serve = serve(settings::<Self, T>(config.clone(), None, Some(incoming)), |_addr, _msg: T| async {});
+        async move
+        {
+            listen(addr, config, vec![Route::any(serve)]);
+            receiver.filter_map(|(addr, payload)| async move
             {
-                debug!("Старт сервера websocket...");
-                // Create the event loop and TCP listener we'll accept connections on.
-                let listener = tokio::net::TcpListener::bind(&addr).await;
-                if let Ok(lis) = listener
+                match serde_json::from_slice::<T>(&payload)
                 {
-                    debug!("Websocet доступен на : {}", &addr);
-                    while let Ok((stream, _)) = lis.accept().await 
+                    Ok(msg) => Some((addr, msg)),
+                    Err(e) =>
                     {
-                        let f = f.clone();
-                        tokio::spawn(async move
-                        {
-                            accept_connection(stream, f).await;
-                        });
+                        error!("Ошибка десериализации обьекта {:?} поступившего от клиента {} ", e.to_string(), &addr);
+                        None
                     }
                 }
-                else
-                {
-                    logger::error!("Ошибка запуска сервера: {}", listener.unwrap_err().to_string())
-                }
-            });
-            ()
+            }).boxed()
         }
     }
+    ///Сведения о подключении клиента __id__, доступны пока клиент подключен
+    fn connection_info(id: &ConnectionId) -> Option<ConnectionInfo>
+    {
+        PEERS.get(id).map(|p| p.value().clone())
+    }
+    ///Отправка сообщений клиентам через `Sink`, ошибки отправки возвращаются как в `try_send`
+    fn sink() -> impl Sink<(ConnectionId, T), Error = SendError> + Send + Unpin
+    {
+        Box::pin(sink::unfold((), |_, (addr, msg): (ConnectionId, T)| async move
+        {
+            Self::try_send(msg, &addr).await
+        }))
+    }
 
     
 
     
-    /// Сообщения всем подключеным клиентам
+    /// Сообщения всем подключеным клиентам, из обработчика точки подключения `Router` только клиентам этой точки подключения
     fn broadcast_message_to_all(msg: T)  -> impl std::future::Future<Output = ()> + Send
     {
+        let scope = Scope::current::<Self, T>();
         async move 
         {
-            let state = CLIENTS.read().await;
-            //debug!("Отправка сообщений {} клиентам", state.len());
+            //debug!("Отправка сообщений {} клиентам", CLIENTS.len());
             let mut message: Vec<u8> = Vec::new();
             let _ = serde_json::to_writer(&mut message, &msg);
-            let message = Message::Binary(message);
-            for (_, sender) in state.iter()
+            for client in CLIENTS.iter().filter(|c| c.scope == scope)
+            {
+                if let Err(err) = client.sender.send(message.clone())
+                {
+                    error!("{:?}", err);
+                }
+            }
+        }
+    }
+    ///Сообщение всем клиентам подключенным через точку подключения __path__ этого сервера,
+    /// единственный способ отправить сообщение клиентам другой точки подключения
+    fn broadcast_to_endpoint(path: &str, msg: T) -> impl std::future::Future<Output = ()> + Send
+    {
+        let path = router::normalize(path);
+        async move
+        {
+            let Ok(message) = transport::serialize(&msg) else
             {
-                if let Err(err) = sender.unbounded_send(message.clone())
+                return;
+            };
+            for client in CLIENTS.iter().filter(|c| c.scope.is::<Self, T>() && c.scope.path.as_ref() == Some(&path))
+            {
+                if let Err(err) = client.sender.send(message.clone())
                 {
                     error!("{:?}", err);
                 }
             }
         }
     }
-    ///Сообщения всем подключеным клиентам кроме того что передан параметром addr
+    ///Сообщения всем подключеным клиентам кроме того что передан параметром addr,
+    /// если отправитель подключен к этому серверу то только клиентам его точки подключения
     fn message_to_all_except_sender(sender_addr: &SocketAddr, msg: T) -> impl std::future::Future<Output = ()> + Send
     {
+        let scope = CLIENTS.get(sender_addr).map(|c| c.scope.clone()).filter(|s| s.is::<Self, T>()).unwrap_or_else(Scope::current::<Self, T>);
         async move {
-        let state = CLIENTS
-            .read()
-            .await;
         let mut message: Vec<u8> = Vec::new();
         let _ = serde_json::to_writer(&mut message, &msg);
-        let message = Message::Binary(message);
-        for (addr, sender) in state.iter()
+        for client in CLIENTS.iter().filter(|c| c.scope == scope)
         {
-            if sender_addr != addr
+            if sender_addr != client.key()
             {
-                if let Err(err) = sender.unbounded_send(message.clone())
+                if let Err(err) = client.sender.send(message.clone())
                 {
                     error!("{:?}", err);
                 }
@@ -96,63 +241,463 @@ pub trait Server<T> where T: 'static + serde::Serialize + Send + Sync, for <'de>
     {
         async move 
         {
-            let mut message: Vec<u8> = Vec::new();
-            let _ = serde_json::to_writer(&mut message, &msg);
-            let message = Message::Binary(message);
-            if let Some(sender) = CLIENTS.read().await.get(target_addr)
+            if let Err(e) = Self::try_send(msg, target_addr).await
+            {
+                error!("Ошибка отправки сообщения клиенту {} -> {}", target_addr, e);
+            }
+        }
+    }
+    ///Отправка сообщения клиенту, сообщение поставлено в очередь отправки соединения
+    fn try_send(msg: T, target_addr: &SocketAddr) -> impl std::future::Future<Output = Result<(), SendError>> + Send
+    {
+        async move 
+        {
+            let message = transport::serialize(&msg)?;
+            let sender = client::<Self, T>(target_addr).ok_or(SendError::NotConnected)?;
+            sender.send(message)
+        }
+    }
+    ///Отправка сообщения клиенту, завершается когда сообщение записано в сокет
+    fn send_confirmed(msg: T, target_addr: &SocketAddr) -> impl std::future::Future<Output = Result<(), SendError>> + Send
+    {
+        async move 
+        {
+            let message = transport::serialize(&msg)?;
+            let sender = client::<Self, T>(target_addr).ok_or(SendError::NotConnected)?;
+            sender.send_confirmed(message).await
+        }
+    }
+    ///Отправка файла клиенту, если соединение разорвано то после переподключения клиента
+    /// повторный вызов с новым адресом продолжит отправку с последней подтвержденной клиентом позиции
+    fn send_file(path: impl AsRef<Path>, target_addr: &SocketAddr) -> impl std::future::Future<Output = Result<(), TransferError>> + Send
+    {
+        let path = path.as_ref().to_owned();
+        let settings = Self::files().unwrap_or_default();
+        async move
+        {
+            let sender = client::<Self, T>(target_addr).ok_or(TransferError::NotConnected)?;
+            files::send_file(&sender, &path, &settings).await
+        }
+    }
+    ///Ожидание следующего файла принятого от клиентов этого сервера, файлы принимаются только если заданы настройки `files`
+    fn receive_file() -> impl std::future::Future<Output = Option<ReceivedFile>> + Send
+    {
+        let inbox = inbox(&Scope::new::<Self, T>(None));
+        async move
+        {
+            inbox.receive().await
+        }
+    }
+}
+
+///Настройки соединения полученные из реализации трейта
+#[derive(Clone)]
+struct Settings
+{
+    ///сервер через который подключен клиент, рассылка сообщений идет только клиентам своего сервера
+    scope: Scope,
+    batching: Option<BatchSettings>,
+    chunking: Option<ChunkSettings>,
+    on_progress: fn(SocketAddr, ChunkProgress),
+    files: Option<FileSettings>,
+    config: ServerConfig,
+    ///если задан то входящие сообщения передаются в этот канал, а не обработчику
+    incoming: Option<UnboundedSender<(ConnectionId, Vec<u8>)>>
+}
+
+fn settings<S, T>(config: ServerConfig, path: Option<String>, incoming: Option<UnboundedSender<(ConnectionId, Vec<u8>)>>) -> Settings
+where S: Server<T> + ?Sized, T: 'static + serde::Serialize + Send + Sync, for <'de> T : serde::Deserialize<'de> + Sized + Send
+{
+    Settings
+    {
+        scope: Scope::new::<S, T>(path),
+        batching: S::batching(),
+        chunking: S::chunking(),
+        on_progress: S::on_chunk_progress,
+        files: S::files(),
+        config,
+        incoming
+    }
+}
+
+///Канал отправки клиенту __addr__ если он подключен через сервер __S__ с сообщениями __T__
+fn client<S: ?Sized, T>(addr: &SocketAddr) -> Option<Outbound>
+{
+    CLIENTS.get(addr).filter(|c| c.scope.is::<S, T>()).map(|c| c.sender.clone())
+}
+
+///Обработчик подключений с типизированным обработчиком сообщений __f__
+fn serve<F, T, Fut>(settings: Settings, f: F) -> Serve
+where T: 'static + serde::Serialize + Send + Sync, for <'de> T : serde::Deserialize<'de> + Sized + Send,
+    F:  Send + Sync + 'static + Clone + Fn(SocketAddr, T) -> Fut, Fut: std::future::Future<Output = ()> + Send + Sync + 'static
+{
+    Arc::new(move |ws_stream, info, seal| accept_connection(ws_stream, info, seal, f.clone(), settings.clone()).boxed())
+}
+
+///Запуск приема подключений на адресе __addr__, подключение передается точке подключения __routes__ с совпадающим путем запроса
+pub(crate) fn listen(addr: String, config: ServerConfig, routes: Vec<Route>)
+{
+    let listener = Arc::new(Listener { config, routes, connections: DashMap::new() });
+    tokio::spawn(async move
+    {
+        debug!("Старт сервера websocket...");
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix(unix::PREFIX)
+        {
+            match unix::SocketFile::bind(Path::new(path))
+            {
+                //файл сокета удаляется когда задача сервера завершается вместе с рантаймом
+                Ok((unix_listener, _file)) =>
+                {
+                    debug!("Websocet доступен на : {}", &addr);
+                    while let Ok((stream, _)) = unix_listener.accept().await
+                    {
+                        let listener = listener.clone();
+                        tokio::spawn(async move
+                        {
+                            handshake(Box::new(stream), ConnectionInfo { id: unix::peer_id(), path: String::new(), client_subject: None, claims: None }, &listener).await;
+                        });
+                    }
+                },
+                Err(e) => logger::error!("Ошибка запуска сервера: {}", e)
+            }
+            return;
+        }
+        // Create the event loop and TCP listener we'll accept connections on.
+        let tcp = tokio::net::TcpListener::bind(&addr).await;
+        if let Ok(lis) = tcp
+        {
+            debug!("Websocet доступен на : {}", &addr);
+            #[cfg(feature = "tls")]
+            let acceptor = listener.config.tls.as_ref().map(|tls| tls.acceptor());
+            while let Ok((stream, addr)) = lis.accept().await 
             {
-                sender.unbounded_send(message).unwrap();
+                if listener.config.ip_filter.as_ref().is_some_and(|f| !f.check(addr.ip()))
+                {
+                    debug!("Подключение с адреса {} отклонено фильтром адресов", &addr);
+                    continue;
+                }
+                //лишние подключения с одного ip закрываются сразу, без рукопожатия
+                let Some(admitted) = listener.admit(addr) else
+                {
+                    continue;
+                };
+                let listener = listener.clone();
+                #[cfg(feature = "tls")]
+                if let Some(acceptor) = acceptor.clone()
+                {
+                    tokio::spawn(async move
+                    {
+                        let _admitted = admitted;
+                        match acceptor.accept(stream).await
+                        {
+                            Ok(stream) =>
+                            {
+                                let subject = stream.get_ref().1.peer_certificates().and_then(|c| c.first()).and_then(tls::subject);
+                                handshake(Box::new(stream), ConnectionInfo { id: addr, path: String::new(), client_subject: subject, claims: None }, &listener).await
+                            },
+                            Err(e) => logger::warn!("Ошибка TLS рукопожатия с {} -> {}", &addr, e)
+                        }
+                    });
+                    continue;
+                }
+                tokio::spawn(async move
+                {
+                    let _admitted = admitted;
+                    handshake(Box::new(stream), ConnectionInfo { id: addr, path: String::new(), client_subject: None, claims: None }, &listener).await;
+                });
             }
         }
+        else
+        {
+            logger::error!("Ошибка запуска сервера: {}", tcp.unwrap_err().to_string())
+        }
+    });
+}
+
+///Количество подключенных клиентов, для нагрузочных тестов
+#[cfg(test)]
+pub(crate) fn connections_count() -> usize
+{
+    CLIENTS.len()
+}
+
+fn add_message_sender(socket: &SocketAddr, settings: &Settings) -> (Outbound, BoxStream<'static, Outgoing>)
+{
+    let addr = *socket;
+    let on_progress = settings.on_progress;
+    let (sender, receiver) = Outbound::new(settings.batching, settings.chunking, settings.config.send_queue_limit, move |p| on_progress(addr, p));
+    CLIENTS.insert(addr, Connected { scope: settings.scope.clone(), sender: sender.clone() });
+    (sender, receiver)
+}
+///Настройки приема подключений общие для всех точек подключения адреса
+struct Listener
+{
+    config: ServerConfig,
+    routes: Vec<Route>,
+    ///количество подключений с каждого ip адреса
+    connections: DashMap<IpAddr, usize>
+}
+impl Listener
+{
+    ///Учет подключения с адреса __addr__, если с этого ip уже открыто максимальное количество подключений то `None`
+    fn admit(self: &Arc<Self>, addr: SocketAddr) -> Option<Admitted>
+    {
+        let ip = addr.ip();
+        let limit = self.config.limits.as_ref().and_then(|l| l.max_connections_per_ip);
+        let mut count = self.connections.entry(ip).or_insert(0);
+        if limit.is_some_and(|l| *count >= l)
+        {
+            drop(count);
+            self.connections.remove_if(&ip, |_, c| *c == 0);
+            security::emit(SecurityEvent::TooManyConnections { peer: addr });
+            return None;
+        }
+        *count += 1;
+        Some(Admitted { listener: self.clone(), ip })
+    }
+}
+///Учтенное подключение, при завершении подключения счетчик ip адреса уменьшается
+struct Admitted
+{
+    listener: Arc<Listener>,
+    ip: IpAddr
+}
+impl Drop for Admitted
+{
+    fn drop(&mut self)
+    {
+        if let Some(mut count) = self.listener.connections.get_mut(&self.ip)
+        {
+            *count -= 1;
+        }
+        self.listener.connections.remove_if(&self.ip, |_, c| *c == 0);
     }
 }
 
-async fn add_message_sender(socket: &SocketAddr) -> UnboundedReceiver<Message>
+///Ответ на запрос рукопожатия с кодом ошибки __status__
+fn reject(status: StatusCode, reason: String) -> ErrorResponse
+{
+    let mut response = ErrorResponse::new(Some(reason));
+    *response.status_mut() = status;
+    response
+}
+
+///Рукопожатие websocket с клиентом __info__ и выбор точки подключения по пути запроса,
+/// если источник запроса не разрешен то клиенту возвращается 403, если точки подключения с таким путем нет то 404
+async fn handshake(stream: Box<dyn Io>, mut info: ConnectionInfo, listener: &Listener)
+{
+    let mut serve = None;
+    #[cfg_attr(not(feature = "seal"), allow(unused_mut, clippy::let_unit_value))]
+    let mut seal = Seal::default();
+    //тип ответа с ошибкой задан tungstenite
+    #[allow(clippy::result_large_err)]
+    #[cfg_attr(not(feature = "seal"), allow(unused_mut))]
+    let callback = |request: &Request, mut response: Response|
+    {
+        if let Some(origins) = listener.config.origins.as_ref()
+        {
+            let origin = request.headers().get(header::ORIGIN).map(|o| String::from_utf8_lossy(o.as_bytes()).into_owned());
+            if !origins.allows(origin.as_deref())
+            {
+                security::emit(SecurityEvent::OriginRejected { peer: info.id, origin });
+                return Err(reject(StatusCode::FORBIDDEN, "Источник запроса не разрешен".to_owned()));
+            }
+        }
+        #[cfg(feature = "jwt")]
+        if let Some(auth) = listener.config.auth.as_ref()
+        {
+            match jwt::token(request).ok_or(AuthError::MissingToken).and_then(|t| auth.validate(&t))
+            {
+                Ok(claims) => info.claims = Some(claims),
+                Err(e) =>
+                {
+                    security::emit(SecurityEvent::AuthFailed { peer: info.id, reason: e.to_string() });
+                    return Err(reject(StatusCode::UNAUTHORIZED, e.to_string()));
+                }
+            }
+        }
+        let path = request.uri().path();
+        match listener.routes.iter().find(|r| r.matches(path))
+        {
+            Some(route) =>
+            {
+                #[cfg(feature = "seal")]
+                if let Some(sealing) = listener.config.sealing.as_ref()
+                {
+                    match sealing.accept(request.headers(), response.headers_mut())
+                    {
+                        Ok(channel) => seal = Some(channel),
+                        Err(e) =>
+                        {
+                            security::emit(SecurityEvent::AuthFailed { peer: info.id, reason: e.to_string() });
+                            return Err(reject(StatusCode::UNAUTHORIZED, e.to_string()));
+                        }
+                    }
+                }
+                serve = Some(route.serve.clone());
+                info.path = path.to_owned();
+                Ok(response)
+            },
+            None =>
+            {
+                logger::warn!("Клиент {} запросил неизвестный путь {}", &info.id, path);
+                Err(reject(StatusCode::NOT_FOUND, format!("Путь {} не найден", path)))
+            }
+        }
+    };
+    match tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(listener.config.websocket)).await
+    {
+        Ok(ws_stream) => if let Some(serve) = serve
+        {
+            serve(ws_stream, info, seal).await
+        },
+        Err(e) => logger::warn!("Ошибка websocket рукопожатия с {} -> {}", &info.id, e)
+    }
+}
+///Превышение ограничения скорости клиентом __addr__: событие безопасности и ответ клиенту,
+/// возвращает true если соединение закрывается
+fn limit_exceeded(addr: SocketAddr, violation: Violation, sender: &Outbound) -> bool
+{
+    if violation.first
+    {
+        security::emit(SecurityEvent::RateLimited { peer: addr, action: violation.action });
+    }
+    if let Some(reply) = violation.reply()
+    {
+        let _ = sender.send_message(reply);
+    }
+    violation.action == LimitAction::Close
+}
+///Новый токен присланный клиентом __addr__ без переподключения, если токен не прошел проверку то соединение закрывается,
+/// возвращает true если соединение закрывается
+#[cfg(feature = "jwt")]
+fn reauthenticate(addr: SocketAddr, session: &jwt::Session, data: &[u8], sender: &Outbound) -> bool
+{
+    let Some(AuthFrame::Token(token)) = auth::decode(data) else
+    {
+        return false;
+    };
+    match session.refresh(&token)
+    {
+        Ok(claims) =>
+        {
+            debug!("Клиент {} обновил токен", &addr);
+            if let Some(mut peer) = PEERS.get_mut(&addr)
+            {
+                peer.claims = Some(claims);
+            }
+            false
+        },
+        Err(e) =>
+        {
+            security::emit(SecurityEvent::AuthFailed { peer: addr, reason: e.to_string() });
+            let frame = CloseFrame { code: CloseCode::Policy, reason: e.to_string().into() };
+            let _ = sender.send_message(Message::Close(Some(frame)));
+            true
+        }
+    }
+}
+///Фрейм клиента __addr__ не прошел проверку подписи: событие безопасности и закрытие соединения с кодом 1008
+#[cfg(feature = "seal")]
+fn frame_rejected(addr: SocketAddr, error: SealError, sender: &Outbound)
 {
-    let (sender, receiver) = unbounded();
-    let mut guard =   CLIENTS.write().await;
-    guard.insert(socket.clone(), sender);
-    drop(guard);
-    receiver
+    security::emit(SecurityEvent::FrameRejected { peer: addr, reason: error.to_string() });
+    let frame = CloseFrame { code: CloseCode::Policy, reason: error.to_string().into() };
+    let _ = sender.send_message(Message::Close(Some(frame)));
 }
-async fn accept_connection<F, T : 'static,  Fut: std::future::Future<Output = ()> + Send + Sync>(stream: tokio::net::TcpStream, f:F)
-    where T: serde::Serialize + Send + Sync, for <'de> T : serde::Deserialize<'de> + Sized + Send, F:  Send + Clone + 'static + Fn(SocketAddr, T) -> Fut
+///Обработка подключения клиента __info__ после рукопожатия, __seal__ подпись фреймов согласованная при рукопожатии
+async fn accept_connection<F, T, Fut>(ws_stream: WebSocketStream<Box<dyn Io>>, info: ConnectionInfo, #[cfg_attr(not(feature = "seal"), allow(unused_variables))] seal: Seal, f:F, settings: Settings)
+    where T: 'static + serde::Serialize + Send + Sync, Fut: std::future::Future<Output = ()> + Send + Sync, for <'de> T : serde::Deserialize<'de> + Sized + Send, F:  Send + Clone + 'static + Fn(SocketAddr, T) -> Fut
     {
-        let addr = stream.peer_addr().expect("Соединение должно иметь исходящий ip адрес");
-        // let headers_callback = |req: &Request, mut response: Response| 
-        // {
-        //     debug!("Получен новый ws handshake от {}", &addr);
-        //     debug!("Путь запроса: {}", req.uri().path());
-        //     debug!("Хэдеры запроса:");
-        //     for (ref header, _value) in req.headers() 
-        //     {
-        //         debug!("* {}: {:?}", header, _value);
-        //     }
-        //     Ok(response)
-        // };
-        let ws_stream = tokio_tungstenite::accept_async(stream)
-            .await
-            .expect("Ошибка handsnake при извлечении данных из websocket");
-        let receiver = add_message_sender(&addr).await;
+        let addr = info.id;
+        #[cfg(feature = "jwt")]
+        let session = jwt::Session::new(settings.config.auth.clone(), info.claims.as_ref());
+        PEERS.insert(addr, info);
+        let (sender, receiver) = add_message_sender(&addr, &settings);
         let (outgoing, incoming) = ws_stream.split();
-       
+        let files = files::spawn_handler(sender.clone(), settings.files.clone(), inbox(&settings.scope), Some(addr));
+        let on_progress = settings.on_progress;
+        let mut inbound = Inbound::new(settings.chunking, settings.config.websocket.max_message_size, move |p| on_progress(addr, p), files);
+        let heartbeat = Heartbeat::new(settings.config.heartbeat);
+        let mut limiter = Limiter::new(settings.config.limits.as_ref());
+        #[cfg(feature = "seal")]
+        let (sealer, mut opener) = seal.unzip();
+        //после отправки фрейма закрытия (превышено ограничение с действием `Close`) сообщения не обрабатываются,
+        //ответного фрейма закрытия от клиента ждем не дольше CLOSE_TIMEOUT
+        let closing = tokio::sync::watch::Sender::new(false);
         let from_ws = incoming.try_for_each(|msg| 
         {
+            heartbeat.received();
             if !msg.is_ping() && !msg.is_pong() && !msg.is_empty() && !msg.is_close()
             {
-                let msg = serde_json::from_slice::<T>(&msg.into_data()).with_context(|| format!("Данный объект отличается от того который вы хотите получить"));
-                if let Ok(d) = msg
+                if *closing.borrow()
+                {
+                    return future::ok(());
+                }
+                if let Some(violation) = limiter.frame(msg.len())
                 {
-                    logger::info!("Сервер получил новое сообщение");
-                    let f = f.clone();
-                    tokio::spawn(async move 
+                    closing.send_replace(limit_exceeded(addr, violation, &sender));
+                    return future::ok(());
+                }
+                let data = msg.into_data();
+                #[cfg(feature = "seal")]
+                let data = match opener.as_mut().map(|o| o.open(&data))
+                {
+                    Some(Ok(data)) => data,
+                    Some(Err(e)) =>
                     {
-                        f(addr.clone(), d).await;
-                    });
+                        frame_rejected(addr, e, &sender);
+                        closing.send_replace(true);
+                        return future::ok(());
+                    },
+                    None => data
+                };
+                if auth::is_auth_frame(&data)
+                {
+                    #[cfg(feature = "jwt")]
+                    {
+                        closing.send_replace(reauthenticate(addr, &session, &data, &sender));
+                    }
+                    return future::ok(());
+                }
+                let payloads = inbound.payloads(data);
+                if let Err(e) = payloads.as_ref()
+                {
+                    error!("Ошибка распаковки сообщения поступившего от клиента {} -> {}", &addr, e);
+                    return future::ok(());
                 }
-                else
+                for payload in payloads.unwrap()
                 {
-                    error!("Ошибка десериализации обьекта {:?} поступившего от клиента {} ", &msg.err().unwrap().to_string(), &addr);
+                    if let Some(violation) = limiter.message()
+                    {
+                        if limit_exceeded(addr, violation, &sender)
+                        {
+                            closing.send_replace(true);
+                            break;
+                        }
+                        continue;
+                    }
+                    if let Some(incoming) = settings.incoming.as_ref()
+                    {
+                        let _ = incoming.unbounded_send((addr, payload));
+                        continue;
+                    }
+                    let msg = serde_json::from_slice::<T>(&payload).context("Данный объект отличается от того который вы хотите получить");
+                    if let Ok(d) = msg
+                    {
+                        logger::info!("Сервер получил новое сообщение");
+                        let f = f.clone();
+                        //рассылки из обработчика доходят только до клиентов его точки подключения
+                        tokio::spawn(ENDPOINT.scope(settings.scope.path.clone(), async move 
+                        {
+                            f(addr, d).await;
+                        }));
+                    }
+                    else
+                    {
+                        error!("Ошибка десериализации обьекта {:?} поступившего от клиента {} ", &msg.err().unwrap().to_string(), &addr);
+                    }
                 }
             }
             else if msg.is_ping()
@@ -161,28 +706,58 @@ async fn accept_connection<F, T : 'static,  Fut: std::future::Future<Output = ()
             }
             else if msg.is_close()
             {
-                tokio::task::spawn(async move
-                {
-                    debug!("Сервером получено сообщение is_close {}", &addr);
-                    let mut guard = CLIENTS.write().await;
-                    guard.remove(&addr.clone());
-                    drop(guard);
-                });
+                debug!("Сервером получено сообщение is_close {}", &addr);
+                CLIENTS.remove(&addr);
             }
             future::ok(())
         });
         //let tt = tokio::spawn(receiver.map(Ok).forward(outgoing));
-        let send_to_ws = receiver.map(Ok).forward(outgoing);
-        pin_mut!(from_ws);
-        let _ = future::select(from_ws, send_to_ws).await;
-        let mut guard = CLIENTS.write().await;
-        guard.remove(&addr);
-        drop(guard);
+        let writer = Writer::new(outgoing);
+        #[cfg(feature = "seal")]
+        let writer = writer.sealed(sealer);
+        let send_to_ws = receiver.map(Ok).forward(writer);
+        let dead = heartbeat.run(&sender);
+        #[cfg(feature = "jwt")]
+        let expired = session.run(&sender).map(|_| security::emit(SecurityEvent::TokenExpired { peer: addr }));
+        #[cfg(not(feature = "jwt"))]
+        let expired = future::pending::<()>();
+        let mut closed = closing.subscribe();
+        let closed = async move
+        {
+            let _ = closed.wait_for(|c| *c).await;
+        };
+        pin_mut!(from_ws, dead, expired, closed);
+        match future::select(future::select(from_ws, send_to_ws), future::select(dead, future::select(expired, closed))).await
+        {
+            Either::Left((Either::Left((Err(e), send_to_ws)), _)) => transport::close_with_error(&e, &sender, send_to_ws).await,
+            Either::Right((Either::Left(_), _)) => logger::warn!("Клиент {} не ответил на ping, соединение разорвано", &addr),
+            Either::Right((Either::Right(_), connection)) =>
+            {
+                //фрейм закрытия уже в очереди, ждем ответного фрейма закрытия от клиента
+                let _ = tokio::time::timeout(transport::CLOSE_TIMEOUT, connection).await;
+            },
+            _ => ()
+        }
+        CLIENTS.remove(&addr);
+        PEERS.remove(&addr);
         debug!("Клиент {} отсоединен", &addr);
     }
 #[cfg(test)]
 mod tests
 {
-   
+    use std::sync::Arc;
+    use super::{inbox, Scope};
+
+    #[test]
+    fn test_inbox_scope()
+    {
+        struct First;
+        struct Second;
+        //точки подключения одного сервера делят очередь файлов, у другого сервера своя очередь
+        let first = inbox(&Scope::new::<First, String>(None));
+        assert!(Arc::ptr_eq(&first, &inbox(&Scope::new::<First, String>(Some("/upload".to_owned())))));
+        assert!(!Arc::ptr_eq(&first, &inbox(&Scope::new::<Second, String>(None))));
+        assert!(!Arc::ptr_eq(&first, &inbox(&Scope::new::<First, u32>(None))));
+    }
 }
 
@@ -0,0 +1,119 @@
+use std::{fmt::Display, time::Duration};
+use futures::{future, stream::{self, BoxStream}, StreamExt};
+use tokio::sync::{broadcast, watch};
+
+///Состояние подключения клиента
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientState
+{
+    ///подключение к серверу __endpoint__
+    Connecting
+    {
+        endpoint: String
+    },
+    ///клиент подключен к серверу __endpoint__
+    Connected
+    {
+        endpoint: String
+    },
+    Disconnected(DisconnectReason),
+    ///ожидание перед следующей попыткой подключения
+    Reconnecting
+    {
+        ///номер попытки переподключения начиная с 1, сбрасывается после успешного подключения
+        attempt: u32,
+        delay: Duration
+    },
+    ///клиент остановлен вызовом `close` или закончились попытки переподключения
+    Closed
+}
+
+///Причина разрыва соединения
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason
+{
+    ///не удалось подключиться к серверу
+    ConnectFailed(String),
+    ///сервер закрыл соединение, если сервер не указал код то код 1005
+    ServerClosed
+    {
+        code: u16,
+        reason: String
+    },
+    ///ошибка чтения или записи, соединение разорвано
+    Error(String),
+    ///сервер не ответил на ping вовремя
+    HeartbeatTimeout,
+    ///основной сервер снова доступен, клиент отключился от резервного сервера чтобы вернуться на основной
+    PrimaryRecovered,
+    ///соединение закрыто вызовом `close`
+    ClientClosed
+}
+impl Display for DisconnectReason
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            DisconnectReason::ConnectFailed(e) => write!(f, "Ошибка подключения к серверу: {}", e),
+            DisconnectReason::ServerClosed { code, reason } => write!(f, "Сервер закрыл соединение с кодом {}: {}", code, reason),
+            DisconnectReason::Error(e) => write!(f, "Соединение разорвано: {}", e),
+            DisconnectReason::HeartbeatTimeout => f.write_str("Сервер не ответил на ping"),
+            DisconnectReason::PrimaryRecovered => f.write_str("Основной сервер снова доступен, переключение на основной сервер"),
+            DisconnectReason::ClientClosed => f.write_str("Соединение закрыто клиентом")
+        }
+    }
+}
+
+///Ошибка первого подключения клиента
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectError
+{
+    ///за отведенное время рукопожатие не завершилось
+    Timeout,
+    ///не удалось подключиться ни к одному серверу, последняя ошибка подключения
+    Failed(String),
+    ///клиент остановлен до завершения рукопожатия
+    Closed
+}
+impl Display for ConnectError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            ConnectError::Timeout => f.write_str("Превышено время ожидания подключения к серверу"),
+            ConnectError::Failed(e) => write!(f, "Ошибка подключения к серверу: {}", e),
+            ConnectError::Closed => f.write_str("Клиент остановлен до подключения к серверу")
+        }
+    }
+}
+impl std::error::Error for ConnectError {}
+
+///Сколько изменений состояния хранится для подписчиков которые не успевают их читать
+pub(crate) const EVENTS_CAPACITY: usize = 32;
+
+///Поток состояний: сначала текущее состояние, затем каждое изменение,
+/// если подписчик отстал больше чем на `EVENTS_CAPACITY` изменений то старые изменения пропускаются
+pub(crate) fn changes(current: &watch::Receiver<ClientState>, events: broadcast::Receiver<ClientState>) -> BoxStream<'static, ClientState>
+{
+    let current = current.borrow().clone();
+    let changes = stream::unfold(events, |mut events| async move
+    {
+        loop
+        {
+            match events.recv().await
+            {
+                Ok(state) => return Some((state, events)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None
+            }
+        }
+    });
+    //изменение произошедшее во время подписки может прийти повторно
+    let mut last = None;
+    stream::once(async move { current })
+        .chain(changes)
+        .filter(move |state| future::ready(last.replace(state.clone()).as_ref() != Some(state)))
+        .boxed()
+}
@@ -0,0 +1,335 @@
+use std::{fmt::Display, path::{Path, PathBuf}, sync::Arc};
+#[cfg(feature = "server")]
+use std::sync::RwLock;
+#[cfg(feature = "client")]
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{self, crypto::{ring, CryptoProvider}, pki_types::{CertificateDer, PrivateKeyDer}, RootCertStore};
+#[cfg(feature = "server")]
+use tokio_rustls::rustls::{server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier}, sign::CertifiedKey};
+#[cfg(feature = "client")]
+use tokio_rustls::{client::TlsStream, rustls::{client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}, pki_types::{ServerName, UnixTime}, DigitallySignedStruct, SignatureScheme}};
+
+///Ошибка загрузки сертификатов
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsError
+{
+    ///не удалось прочитать файл
+    Io
+    {
+        path: PathBuf,
+        error: String
+    },
+    ///в файле нет ни одного сертификата
+    NoCertificates(PathBuf),
+    ///в файле нет закрытого ключа
+    NoPrivateKey(PathBuf),
+    ///ключ не подходит или не поддерживается
+    InvalidKey(String),
+    ///сертификат не может быть использован как корневой
+    InvalidCertificate(String)
+}
+impl Display for TlsError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            TlsError::Io { path, error } => write!(f, "Ошибка чтения файла {} -> {}", path.display(), error),
+            TlsError::NoCertificates(path) => write!(f, "В файле {} не найдено ни одного сертификата", path.display()),
+            TlsError::NoPrivateKey(path) => write!(f, "В файле {} не найден закрытый ключ", path.display()),
+            TlsError::InvalidKey(e) => write!(f, "Ошибка загрузки закрытого ключа: {}", e),
+            TlsError::InvalidCertificate(e) => write!(f, "Ошибка загрузки корневого сертификата: {}", e)
+        }
+    }
+}
+impl std::error::Error for TlsError {}
+
+fn provider() -> Arc<CryptoProvider>
+{
+    Arc::new(ring::default_provider())
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError>
+{
+    std::fs::read(path).map_err(|e| TlsError::Io { path: path.to_owned(), error: e.to_string() })
+}
+
+///Чтение цепочки сертификатов из pem файла
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError>
+{
+    let data = read(path)?;
+    let certs = rustls_pemfile::certs(&mut data.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Io { path: path.to_owned(), error: e.to_string() })?;
+    if certs.is_empty()
+    {
+        return Err(TlsError::NoCertificates(path.to_owned()));
+    }
+    Ok(certs)
+}
+
+///Чтение закрытого ключа (pkcs8, pkcs1 или sec1) из pem файла
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError>
+{
+    let data = read(path)?;
+    rustls_pemfile::private_key(&mut data.as_slice())
+        .map_err(|e| TlsError::Io { path: path.to_owned(), error: e.to_string() })?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_owned()))
+}
+
+///Добавление сертификатов из pem файла __path__ в список доверенных
+fn add_roots(roots: &mut RootCertStore, path: &Path) -> Result<(), TlsError>
+{
+    for cert in load_certs(path)?
+    {
+        roots.add(cert).map_err(|e| TlsError::InvalidCertificate(e.to_string()))?;
+    }
+    Ok(())
+}
+
+///Субъект сертификата в виде строки `CN=..., O=...`
+#[cfg(feature = "server")]
+pub(crate) fn subject(cert: &CertificateDer<'_>) -> Option<String>
+{
+    x509_parser::parse_x509_certificate(cert).ok().map(|(_, cert)| cert.subject().to_string())
+}
+
+///Сертификат сервера и его ключ, подставляются в каждое новое рукопожатие
+#[cfg(feature = "server")]
+#[derive(Debug)]
+struct CertStore
+{
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>
+}
+#[cfg(feature = "server")]
+impl CertStore
+{
+    fn load(cert: &Path, key: &Path) -> Result<CertifiedKey, TlsError>
+    {
+        let certs = load_certs(cert)?;
+        let key = provider().key_provider.load_private_key(load_key(key)?).map_err(|e| TlsError::InvalidKey(e.to_string()))?;
+        Ok(CertifiedKey::new(certs, key))
+    }
+}
+#[cfg(feature = "server")]
+impl ResolvesServerCert for CertStore
+{
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>>
+    {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+///Настройки TLS сервера, сервер принимает подключения по `wss://`
+/// сертификат можно перезагрузить из файлов вызовом `reload` (или по сигналу SIGHUP через `reload_on_signal`),
+/// уже установленные соединения при этом не разрываются, новый сертификат используется для новых подключений
+#[cfg(feature = "server")]
+#[derive(Debug, Clone)]
+pub struct ServerTls
+{
+    store: Arc<CertStore>,
+    ///если задан то клиенты должны предъявить сертификат подписанный одним из этих сертификатов
+    client_ca: Option<Arc<RootCertStore>>
+}
+#[cfg(feature = "server")]
+impl ServerTls
+{
+    ///Загрузка цепочки сертификатов __cert__ и закрытого ключа __key__ из pem файлов
+    pub fn from_pem(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Result<Self, TlsError>
+    {
+        let cert = cert.into();
+        let key = key.into();
+        let current = CertStore::load(&cert, &key)?;
+        Ok(Self
+        {
+            store: Arc::new(CertStore
+            {
+                cert,
+                key,
+                current: RwLock::new(Arc::new(current))
+            }),
+            client_ca: None
+        })
+    }
+    ///Взаимная аутентификация: клиент должен предъявить сертификат подписанный сертификатом из pem файла __ca__,
+    /// субъект сертификата клиента доступен через `Server::connection_info`
+    pub fn with_client_ca(mut self, ca: impl AsRef<Path>) -> Result<Self, TlsError>
+    {
+        let mut roots = self.client_ca.take().map(|r| (*r).clone()).unwrap_or_else(RootCertStore::empty);
+        add_roots(&mut roots, ca.as_ref())?;
+        self.client_ca = Some(Arc::new(roots));
+        Ok(self)
+    }
+    ///Повторная загрузка сертификата и ключа из тех же файлов, при ошибке остается старый сертификат
+    pub fn reload(&self) -> Result<(), TlsError>
+    {
+        let current = CertStore::load(&self.store.cert, &self.store.key)?;
+        *self.store.current.write().unwrap() = Arc::new(current);
+        logger::info!("Сертификат {} перезагружен", self.store.cert.display());
+        Ok(())
+    }
+    ///Перезагрузка сертификата при получении процессом сигнала SIGHUP
+    #[cfg(unix)]
+    pub fn reload_on_signal(&self) -> std::io::Result<()>
+    {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let tls = self.clone();
+        tokio::spawn(async move
+        {
+            while hangup.recv().await.is_some()
+            {
+                if let Err(e) = tls.reload()
+                {
+                    logger::error!("{}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+    pub(crate) fn acceptor(&self) -> tokio_rustls::TlsAcceptor
+    {
+        let builder = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .expect("Протоколы по умолчанию поддерживаются провайдером ring");
+        let builder = match self.client_ca.clone()
+        {
+            Some(roots) => builder.with_client_cert_verifier(WebPkiClientVerifier::builder_with_provider(roots, provider())
+                .build()
+                .expect("Список корневых сертификатов не пустой")),
+            None => builder.with_no_client_auth()
+        };
+        let config = builder.with_cert_resolver(self.store.clone());
+        tokio_rustls::TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+///Настройки TLS клиента для подключения по `wss://`
+/// сервер проверяется по корневым сертификатам из заданных pem файлов, системные сертификаты не используются
+#[cfg(feature = "client")]
+#[derive(Debug, Clone)]
+pub struct ClientTls
+{
+    roots: RootCertStore,
+    ///сертификат и ключ клиента для взаимной аутентификации
+    identity: Option<(Vec<CertificateDer<'static>>, Arc<PrivateKeyDer<'static>>)>,
+    verify: bool
+}
+#[cfg(feature = "client")]
+impl ClientTls
+{
+    ///Доверять серверам с сертификатами подписанными сертификатами из pem файла __ca__
+    pub fn from_pem(ca: impl AsRef<Path>) -> Result<Self, TlsError>
+    {
+        Self
+        {
+            roots: RootCertStore::empty(),
+            identity: None,
+            verify: true
+        }.add_roots(ca)
+    }
+    ///Добавление корневых сертификатов из еще одного pem файла
+    pub fn add_roots(mut self, ca: impl AsRef<Path>) -> Result<Self, TlsError>
+    {
+        add_roots(&mut self.roots, ca.as_ref())?;
+        Ok(self)
+    }
+    ///Без проверки сертификата сервера, только для разработки
+    pub fn insecure() -> Self
+    {
+        Self
+        {
+            roots: RootCertStore::empty(),
+            identity: None,
+            verify: false
+        }
+    }
+    ///Сертификат __cert__ и ключ __key__ клиента (pem файлы) для взаимной аутентификации
+    pub fn with_identity(mut self, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self, TlsError>
+    {
+        let certs = load_certs(cert.as_ref())?;
+        let key = load_key(key.as_ref())?;
+        provider().key_provider.load_private_key(key.clone_key()).map_err(|e| TlsError::InvalidKey(e.to_string()))?;
+        self.identity = Some((certs, Arc::new(key)));
+        Ok(self)
+    }
+    fn config(&self) -> Result<rustls::ClientConfig, rustls::Error>
+    {
+        let builder = rustls::ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+        let builder = if self.verify
+        {
+            builder.with_root_certificates(self.roots.clone())
+        }
+        else
+        {
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(NoVerification(provider())))
+        };
+        match self.identity.as_ref()
+        {
+            Some((certs, key)) => builder.with_client_auth_cert(certs.clone(), key.clone_key()),
+            None => Ok(builder.with_no_client_auth())
+        }
+    }
+    ///TLS рукопожатие с сервером __host__ поверх __tcp__
+    pub(crate) async fn connect(&self, host: &str, tcp: TcpStream) -> std::io::Result<TlsStream<TcpStream>>
+    {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+        if !self.verify
+        {
+            logger::warn!("Сертификат сервера {} не проверяется", host);
+        }
+        let config = self.config().map_err(|e| invalid(e.to_string()))?;
+        let name = ServerName::try_from(host.to_owned()).map_err(|e| invalid(e.to_string()))?;
+        tokio_rustls::TlsConnector::from(Arc::new(config)).connect(name, tcp).await
+    }
+}
+
+///Принимает любой сертификат сервера, подписи рукопожатия проверяются
+#[cfg(feature = "client")]
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+#[cfg(feature = "client")]
+impl ServerCertVerifier for NoVerification
+{
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error>
+    {
+        Ok(ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error>
+    {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error>
+    {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme>
+    {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests
+{
+    use super::{ServerTls, TlsError};
+
+    #[test]
+    fn test_load_pem()
+    {
+        let dir = std::env::temp_dir().join("websocket_service_test").join("tls_load");
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        let tls = ServerTls::from_pem(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+        //ключ вместо сертификата
+        assert_eq!(ServerTls::from_pem(dir.join("key.pem"), dir.join("key.pem")).unwrap_err(), TlsError::NoCertificates(dir.join("key.pem")));
+        assert_eq!(ServerTls::from_pem(dir.join("cert.pem"), dir.join("cert.pem")).unwrap_err(), TlsError::NoPrivateKey(dir.join("cert.pem")));
+        //при ошибке перезагрузки остается старый сертификат
+        std::fs::write(dir.join("cert.pem"), "").unwrap();
+        assert!(tls.reload().is_err());
+        let _ = tls.acceptor();
+    }
+}
@@ -0,0 +1,358 @@
+use std::{fmt::Display, pin::Pin, sync::{atomic::{AtomicUsize, Ordering}, Arc}, task::{ready, Context, Poll}, time::Duration};
+use futures::{stream::{self, BoxStream, PollNext}, Future, Sink, StreamExt};
+use anyhow::Context as _;
+use futures_channel::{mpsc::{unbounded, UnboundedSender}, oneshot};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::{error::Error, protocol::{frame::coding::CloseCode, CloseFrame}, Message};
+#[cfg(feature = "seal")]
+use crate::seal;
+use crate::{batch, chunk::{self, Reassembler}, files::{self, Transfers}, limits, BatchSettings, ChunkProgress, ChunkSettings};
+
+///Ошибка отправки сообщения
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError
+{
+    ///нет подключения
+    NotConnected,
+    ///превышено количество сообщений ожидающих отправки
+    QueueFull,
+    ///соединение закрыто прежде чем сообщение было записано в сокет
+    Closed,
+    ///ошибка сериализации сообщения
+    Serialization(String)
+}
+impl Display for SendError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            SendError::NotConnected => f.write_str("Нет подключения для отправки сообщения"),
+            SendError::QueueFull => f.write_str("Очередь отправки сообщений переполнена"),
+            SendError::Closed => f.write_str("Соединение закрыто до отправки сообщения"),
+            SendError::Serialization(e) => write!(f, "Ошибка сериализации сообщения: {}", e)
+        }
+    }
+}
+impl std::error::Error for SendError {}
+
+pub(crate) fn serialize<T>(msg: &T) -> Result<Vec<u8>, SendError> where T: serde::Serialize
+{
+    serde_json::to_vec(msg).map_err(|e| SendError::Serialization(e.to_string()))
+}
+
+///Подтверждение записи фрейма в сокет
+pub(crate) type Ack = oneshot::Sender<()>;
+
+///Фрейм ожидающий отправки
+pub(crate) struct Outgoing
+{
+    pub message: Message,
+    ///подтверждения которые отправляются после записи фрейма в сокет
+    pub acks: Vec<Ack>,
+    ///сколько сообщений из очереди отправки содержит фрейм, служебные фреймы не учитываются
+    pub count: usize
+}
+impl Outgoing
+{
+    ///Служебный фрейм (ping, close, передача файлов)
+    pub fn control(message: Message) -> Self
+    {
+        Self { message, acks: Vec::new(), count: 0 }
+    }
+}
+
+///Канал отправки сообщений в соединение
+/// большие сообщения и файлы разбиваются на фрагменты и отправляются отдельным каналом,
+/// обычные сообщения при отправке имеют приоритет над фрагментами
+#[derive(Clone)]
+pub(crate) struct Outbound
+{
+    messages: UnboundedSender<Outgoing>,
+    bulk: UnboundedSender<(Outgoing, Option<ChunkProgress>)>,
+    chunking: Option<ChunkSettings>,
+    transfers: Transfers,
+    ///количество сообщений ожидающих отправки
+    pending: Arc<AtomicUsize>,
+    queue_limit: Option<usize>
+}
+impl Outbound
+{
+    ///Создание канала, возвращается поток фреймов для записи в websocket через `Writer`
+    pub fn new<P>(batching: Option<BatchSettings>, chunking: Option<ChunkSettings>, queue_limit: Option<usize>, on_progress: P) -> (Self, BoxStream<'static, Outgoing>)
+    where P: Fn(ChunkProgress) + Send + 'static
+    {
+        let (messages, messages_receiver) = unbounded();
+        let (bulk, bulk_receiver) = unbounded::<(Outgoing, Option<ChunkProgress>)>();
+        let bulk_receiver = bulk_receiver.map(move |(frame, progress)|
+        {
+            if let Some(progress) = progress
+            {
+                on_progress(progress);
+            }
+            frame
+        });
+        let pending = Arc::new(AtomicUsize::new(0));
+        let sent = pending.clone();
+        let stream = stream::select_with_strategy(batch::outgoing(messages_receiver, batching), bulk_receiver, |_: &mut ()| PollNext::Left)
+            .inspect(move |frame| { sent.fetch_sub(frame.count, Ordering::Relaxed); });
+        (Self { messages, bulk, chunking, transfers: Transfers::default(), pending, queue_limit }, stream.boxed())
+    }
+    ///Отправка сериализованного сообщения
+    pub fn send(&self, payload: Vec<u8>) -> Result<(), SendError>
+    {
+        self.enqueue(payload, None)
+    }
+    ///Отправка сериализованного сообщения, завершается когда сообщение записано в сокет
+    pub fn send_confirmed(&self, payload: Vec<u8>) -> impl Future<Output = Result<(), SendError>>
+    {
+        let (ack, written) = oneshot::channel();
+        let queued = self.enqueue(payload, Some(ack));
+        async move
+        {
+            queued?;
+            written.await.map_err(|_| SendError::Closed)
+        }
+    }
+    fn enqueue(&self, payload: Vec<u8>, ack: Option<Ack>) -> Result<(), SendError>
+    {
+        if self.queue_limit.is_some_and(|limit| self.pending.load(Ordering::Relaxed) >= limit)
+        {
+            return Err(SendError::QueueFull);
+        }
+        if let Some(settings) = self.chunking.as_ref()
+        {
+            if payload.len() > settings.threshold
+            {
+                let chunks = chunk::split(&payload, settings.chunk_size);
+                self.pending.fetch_add(chunks.len(), Ordering::Relaxed);
+                let mut ack = ack;
+                let last = chunks.len() - 1;
+                for (i, (chunk, progress)) in chunks.into_iter().enumerate()
+                {
+                    //подтверждение отправки большого сообщения приходит после записи последнего фрагмента
+                    let acks = if i == last { ack.take().into_iter().collect() } else { Vec::new() };
+                    self.bulk.unbounded_send((Outgoing { message: chunk, acks, count: 1 }, Some(progress))).map_err(|_| SendError::Closed)?;
+                }
+                return Ok(());
+            }
+        }
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let frame = Outgoing { message: Message::Binary(payload), acks: ack.into_iter().collect(), count: 1 };
+        self.messages.unbounded_send(frame).map_err(|_| SendError::Closed)
+    }
+    ///Отправка служебного сообщения (ping, close)
+    pub fn send_message(&self, message: Message) -> Result<(), SendError>
+    {
+        self.messages.unbounded_send(Outgoing::control(message)).map_err(|_| SendError::Closed)
+    }
+    ///Отправка фрейма с низким приоритетом, наравне с фрагментами больших сообщений
+    pub fn send_bulk(&self, message: Message) -> Result<(), SendError>
+    {
+        self.bulk.unbounded_send((Outgoing::control(message), None)).map_err(|_| SendError::Closed)
+    }
+    pub fn transfers(&self) -> &Transfers
+    {
+        &self.transfers
+    }
+}
+
+///Запись фреймов в websocket, подтверждения отправляются после того как фреймы записаны в сокет
+pub(crate) struct Writer<S>
+{
+    sink: S,
+    acks: Vec<Ack>,
+    #[cfg(feature = "seal")]
+    sealer: Option<seal::Sealer>
+}
+impl<S> Writer<S>
+{
+    pub fn new(sink: S) -> Self
+    {
+        Self
+        {
+            sink,
+            acks: Vec::new(),
+            #[cfg(feature = "seal")]
+            sealer: None
+        }
+    }
+    ///Фреймы с данными подписываются и шифруются перед записью в сокет
+    #[cfg(feature = "seal")]
+    pub fn sealed(mut self, sealer: Option<seal::Sealer>) -> Self
+    {
+        self.sealer = sealer;
+        self
+    }
+    fn confirm(&mut self)
+    {
+        for ack in self.acks.drain(..)
+        {
+            let _ = ack.send(());
+        }
+    }
+}
+impl<S> Sink<Outgoing> for Writer<S> where S: Sink<Message> + Unpin
+{
+    type Error = S::Error;
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>
+    {
+        Pin::new(&mut self.sink).poll_ready(cx)
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: Outgoing) -> Result<(), Self::Error>
+    {
+        self.acks.extend(item.acks);
+        #[cfg(feature = "seal")]
+        let message = match self.sealer.as_mut()
+        {
+            Some(sealer) => sealer.seal(item.message),
+            None => item.message
+        };
+        #[cfg(not(feature = "seal"))]
+        let message = item.message;
+        Pin::new(&mut self.sink).start_send(message)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>
+    {
+        ready!(Pin::new(&mut self.sink).poll_flush(cx))?;
+        self.confirm();
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>
+    {
+        ready!(Pin::new(&mut self.sink).poll_close(cx))?;
+        self.confirm();
+        Poll::Ready(Ok(()))
+    }
+}
+
+///Разбор входящих фреймов соединения: распаковка пакетов и сборка фрагментов,
+/// фреймы передачи файлов передаются обработчику файлов соединения
+pub(crate) struct Inbound<P> where P: Fn(ChunkProgress)
+{
+    ///без настроек фрагментации фрагменты не принимаются
+    reassembler: Option<Reassembler>,
+    on_progress: P,
+    files: UnboundedSender<Vec<u8>>
+}
+impl<P> Inbound<P> where P: Fn(ChunkProgress)
+{
+    ///__max_message_size__ ограничение размера сообщения websocket, собранное из фрагментов сообщение не может быть больше
+    pub fn new(chunking: Option<ChunkSettings>, max_message_size: Option<usize>, on_progress: P, files: UnboundedSender<Vec<u8>>) -> Self
+    {
+        let reassembler = chunking.map(|c| Reassembler::new(max_message_size.map_or(c.max_payload, |m| c.max_payload.min(m))));
+        Self
+        {
+            reassembler,
+            on_progress,
+            files
+        }
+    }
+    ///Сообщения содержащиеся во фрейме, для незавершенного фрагмента возвращается пустой список
+    pub fn payloads(&mut self, data: Vec<u8>) -> anyhow::Result<Vec<Vec<u8>>>
+    {
+        if files::is_file_frame(&data)
+        {
+            self.files.unbounded_send(data).context("Обработчик файлов остановлен")?;
+            return Ok(Vec::new());
+        }
+        if limits::is_notice(&data)
+        {
+            logger::warn!("Вторая сторона ограничила скорость отправки сообщений, продолжить отправку можно через {:?}", limits::retry_after(&data).unwrap_or_default());
+            return Ok(Vec::new());
+        }
+        if chunk::is_chunk(&data)
+        {
+            let reassembler = self.reassembler.as_mut().context("Получен фрагмент сообщения, но фрагментация сообщений не настроена")?;
+            let (progress, complete) = reassembler.push(&data)?;
+            (self.on_progress)(progress);
+            return Ok(complete.into_iter().collect());
+        }
+        Ok(batch::unpack(&data)?.into_iter().map(|p| p.to_vec()).collect())
+    }
+}
+
+///Сколько ждать отправки фрейма закрытия соединения
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+///Фрейм закрытия соединения для ошибки чтения, если о ней надо сообщить второй стороне
+pub(crate) fn close_frame(error: &Error) -> Option<CloseFrame<'static>>
+{
+    match error
+    {
+        Error::Capacity(e) => Some(CloseFrame
+        {
+            code: CloseCode::Size,
+            reason: e.to_string().into()
+        }),
+        _ => None
+    }
+}
+
+///Если о ошибке чтения надо сообщить второй стороне то отправляем ей фрейм закрытия с кодом ошибки
+/// и ждем пока __writer__ его отправит
+pub(crate) async fn close_with_error<W>(error: &Error, sender: &Outbound, writer: W) where W: Future
+{
+    if let Some(frame) = close_frame(error)
+    {
+        logger::warn!("Соединение закрыто с кодом {}: {}", frame.code, &frame.reason);
+        if sender.send_message(Message::Close(Some(frame))).is_ok()
+        {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, writer).await;
+        }
+    }
+}
+
+///Соединение поверх которого работает websocket: tcp, unix сокет или TLS поверх tcp
+pub(crate) trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S> Io for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
+
+#[cfg(test)]
+mod tests
+{
+    use futures::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+    use std::sync::atomic::Ordering;
+    use crate::{chunk, ChunkSettings};
+    use super::{Inbound, Outbound, SendError, Writer};
+
+    #[tokio::test]
+    async fn test_queue_limit_and_confirmation()
+    {
+        let (sender, stream) = Outbound::new(None, None, Some(2), |_| ());
+        assert!(sender.send(vec![1]).is_ok());
+        assert!(sender.send(vec![2]).is_ok());
+        assert_eq!(sender.send(vec![3]), Err(SendError::QueueFull));
+        //служебные сообщения не ограничиваются
+        assert!(sender.send_message(Message::Ping(vec![1])).is_ok());
+        let writer = tokio::spawn(stream.map(Ok).forward(Writer::new(futures::sink::drain())));
+        //после записи очередь освобождается
+        while sender.pending.load(Ordering::Relaxed) > 0
+        {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(sender.send_confirmed(vec![4]).await, Ok(()));
+        drop(sender);
+        writer.await.unwrap().unwrap();
+        let (sender, stream) = Outbound::new(None, None, None, |_| ());
+        drop(stream);
+        assert_eq!(sender.send_confirmed(vec![1]).await, Err(SendError::Closed));
+    }
+
+    #[test]
+    fn test_inbound_chunking()
+    {
+        let payload = vec![1u8; 1000];
+        let chunks: Vec<Vec<u8>> = chunk::split(&payload, 100).into_iter().map(|(m, _)| m.into_data()).collect();
+        let (files, _receiver) = futures_channel::mpsc::unbounded();
+        //без настроек фрагментации фрагменты отклоняются
+        let mut inbound = Inbound::new(None, None, |_| (), files.clone());
+        assert!(inbound.payloads(chunks[0].clone()).is_err());
+        //собранное сообщение ограничено максимальным размером сообщения websocket
+        let mut inbound = Inbound::new(Some(ChunkSettings::default()), Some(500), |_| (), files.clone());
+        assert!(inbound.payloads(chunks[0].clone()).is_err());
+        let mut inbound = Inbound::new(Some(ChunkSettings::default()), Some(1000), |_| (), files);
+        let received: Vec<Vec<u8>> = chunks.into_iter().flat_map(|c| inbound.payloads(c).unwrap()).collect();
+        assert_eq!(received, vec![payload]);
+    }
+}
@@ -0,0 +1,80 @@
+#[cfg(feature = "server")]
+use std::{net::{Ipv6Addr, SocketAddr}, os::unix::fs::FileTypeExt, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}};
+#[cfg(feature = "server")]
+use tokio::net::UnixListener;
+
+///Префикс адреса unix сокета: `unix:/run/app.sock` для сервера,
+/// для клиента после пути к сокету можно указать путь запроса: `unix:/run/app.sock:/chat`
+pub(crate) const PREFIX: &str = "unix:";
+
+///Разбор адреса клиента на путь к сокету и путь запроса
+#[cfg(feature = "client")]
+pub(crate) fn parse(addr: &str) -> Option<(&str, &str)>
+{
+    let addr = addr.strip_prefix(PREFIX)?;
+    Some(match addr.split_once(":/")
+    {
+        Some((socket, _)) => (socket, &addr[socket.len() + 1..]),
+        None => (addr, "/")
+    })
+}
+
+///Диапазон 100::/64 (RFC 6666) из которого выдаются идентификаторы подключениям через unix сокет
+#[cfg(feature = "server")]
+const PEERS_PREFIX: u128 = 0x0100 << 112;
+#[cfg(feature = "server")]
+static PEERS: AtomicU64 = AtomicU64::new(1);
+
+///У подключения через unix сокет нет сетевого адреса, поэтому ему выдается уникальный адрес из диапазона 100::/64
+#[cfg(feature = "server")]
+pub(crate) fn peer_id() -> SocketAddr
+{
+    let id = PEERS.fetch_add(1, Ordering::Relaxed) as u128;
+    SocketAddr::new(Ipv6Addr::from(PEERS_PREFIX | id).into(), 0)
+}
+
+///Файл сокета сервера, удаляется при остановке сервера
+#[cfg(feature = "server")]
+pub(crate) struct SocketFile(PathBuf);
+#[cfg(feature = "server")]
+impl SocketFile
+{
+    ///Создание сокета __path__, оставшийся от предыдущего запуска файл сокета удаляется (другие файлы не удаляются)
+    pub fn bind(path: &Path) -> std::io::Result<(UnixListener, Self)>
+    {
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+        {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        Ok((listener, Self(path.to_owned())))
+    }
+}
+#[cfg(feature = "server")]
+impl Drop for SocketFile
+{
+    fn drop(&mut self)
+    {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_parse()
+    {
+        use super::parse;
+        assert_eq!(parse("unix:/run/app.sock"), Some(("/run/app.sock", "/")));
+        assert_eq!(parse("unix:/run/app.sock:/chat?room=1"), Some(("/run/app.sock", "/chat?room=1")));
+        assert_eq!(parse("ws://127.0.0.1:3010/"), None);
+    }
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_peer_id()
+    {
+        assert_ne!(super::peer_id(), super::peer_id());
+    }
+}